@@ -5,6 +5,7 @@ extern crate serde_json;
 use std::{slice, str, io, panic};
 use libc::{c_int, size_t};
 use serde_json::error::Error as SerdeError;
+use dehtml::OutputFormat;
 
 pub const ERR_UNSPECIFIED: c_int = -1;
 
@@ -14,6 +15,12 @@ pub const ERR_INVALID_STRING: c_int = -3;
 
 pub const ERR_INVALID_BAD_DOCUMENT: c_int = -4;
 
+pub const ERR_INVALID_FORMAT: c_int = -5;
+
+pub const FORMAT_TUPLE: c_int = 0;
+
+pub const FORMAT_OBJECT: c_int = 1;
+
 #[no_mangle]
 pub unsafe extern "C" fn dehtml_errstr(errno: c_int) -> *const u8 {
     match errno {
@@ -21,6 +28,7 @@ pub unsafe extern "C" fn dehtml_errstr(errno: c_int) -> *const u8 {
         ERR_INSUFFICIENT_BUFFER => "insufficient buffer\0".as_ptr(),
         ERR_INVALID_STRING => "invalid string\0".as_ptr(),
         ERR_INVALID_BAD_DOCUMENT => "invalid document\0".as_ptr(),
+        ERR_INVALID_FORMAT => "invalid format\0".as_ptr(),
         _ => std::ptr::null(),
     }
 }
@@ -33,6 +41,22 @@ pub unsafe extern "C" fn parse_html(
     obuf: *mut u8,
     olen: size_t,
 ) -> c_int {
+    parse_html_format(ibuf, ilen, obuf, olen, FORMAT_TUPLE)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn parse_html_format(
+    ibuf: *const u8,
+    ilen: size_t,
+    obuf: *mut u8,
+    olen: size_t,
+    format: c_int,
+) -> c_int {
+    let format = match format {
+        FORMAT_TUPLE => OutputFormat::Tuple,
+        FORMAT_OBJECT => OutputFormat::Object,
+        _ => return ERR_INVALID_FORMAT,
+    };
     if ibuf.is_null() {
         return ERR_UNSPECIFIED;
     }
@@ -54,7 +78,7 @@ pub unsafe extern "C" fn parse_html(
             Err(_err) => return ERR_INVALID_BAD_DOCUMENT,
         };
         let mut wri = io::Cursor::new(&mut output[..]);
-        match serde_json::ser::to_writer(&mut wri, &node) {
+        match node.to_json_writer(&mut wri, format) {
             Err(SerdeError::Syntax(_, _, _)) => return ERR_UNSPECIFIED,
             Err(SerdeError::Io(_)) => return ERR_INSUFFICIENT_BUFFER,
             Err(SerdeError::FromUtf8(_)) => return ERR_UNSPECIFIED,
@@ -63,3 +87,27 @@ pub unsafe extern "C" fn parse_html(
         wri.position() as c_int
     }).unwrap_or_else(|_| ERR_UNSPECIFIED)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use super::{parse_html_format, ERR_INVALID_FORMAT, FORMAT_OBJECT};
+
+    #[test]
+    fn rejects_unknown_format() {
+        let input = b"<b>hi</b>";
+        let mut output = [0u8; 128];
+        let ret = unsafe {
+            parse_html_format(input.as_ptr(), input.len(), output.as_mut_ptr(), output.len(), 7)
+        };
+        assert_eq!(ret, ERR_INVALID_FORMAT);
+
+        let ret = unsafe {
+            parse_html_format(input.as_ptr(), input.len(), output.as_mut_ptr(), output.len(), FORMAT_OBJECT)
+        };
+        assert!(0 < ret);
+        assert_eq!(str::from_utf8(&output[..ret as usize]).unwrap(),
+            r#"{"root":{"child":{"text":"hi","type":"text"},"type":"bold"},"version":1}"#);
+    }
+}
@@ -0,0 +1,124 @@
+use url;
+
+use super::{GlobalRef, GlobalRefTarget, RefState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveKind {
+    // desuarchive.org/a/thread/123/#456, archived.moe/a/post/456/
+    FoolFuuka,
+    // warosu.org/ck/thread/123#p456
+    Warosu,
+}
+
+#[derive(Clone, Debug)]
+struct ArchiveSite {
+    name: String,
+    host: String,
+    kind: ArchiveKind,
+}
+
+// Maps links into imageboard archives back onto the board, thread and post
+// they mirror.
+#[derive(Clone, Debug)]
+pub struct ArchiveSiteRegistry {
+    sites: Vec<ArchiveSite>,
+}
+
+fn parse_post_fragment(frag: Option<&String>) -> Result<Option<u64>, ()> {
+    // #456, #p456, #q456
+    let frag = match frag {
+        Some(frag) => frag.trim_left_matches(|ch: char| ch == 'p' || ch == 'q'),
+        None => return Ok(None),
+    };
+    if frag.len() == 0 {
+        return Ok(None);
+    }
+    frag.parse().map(Some).map_err(|_| ())
+}
+
+fn archive_target(kind: ArchiveKind, path: &[String], frag: Option<&String>) -> Option<GlobalRefTarget> {
+    // /ck/ is the board index, /faq is one of the site's own pages
+    if path.len() < 2 {
+        return None;
+    }
+    if path[1] == "" {
+        return Some(GlobalRefTarget::Board);
+    }
+    match (kind, &path[1][..]) {
+        (_, "thread") => {
+            let thread = match path.get(2).map(|part| part.parse()) {
+                Some(Ok(thread)) => thread,
+                _ => return None,
+            };
+            match parse_post_fragment(frag) {
+                Ok(Some(post)) => Some(GlobalRefTarget::Post(thread, post)),
+                Ok(None) => Some(GlobalRefTarget::Thread(thread)),
+                Err(()) => None,
+            }
+        },
+        (ArchiveKind::FoolFuuka, "post") => {
+            match path.get(2).map(|part| part.parse()) {
+                Some(Ok(post)) => Some(GlobalRefTarget::Unresolved(post)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+impl ArchiveSiteRegistry {
+    pub fn new() -> ArchiveSiteRegistry {
+        ArchiveSiteRegistry { sites: Vec::new() }
+    }
+
+    // desuarchive, archived.moe, 4plebs and warosu
+    pub fn known_sites() -> ArchiveSiteRegistry {
+        let mut registry = ArchiveSiteRegistry::new();
+        registry.register("desuarchive", "desuarchive.org", ArchiveKind::FoolFuuka);
+        registry.register("archivedmoe", "archived.moe", ArchiveKind::FoolFuuka);
+        registry.register("4plebs", "archive.4plebs.org", ArchiveKind::FoolFuuka);
+        registry.register("warosu", "warosu.org", ArchiveKind::Warosu);
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, host: &str, kind: ArchiveKind) {
+        self.sites.push(ArchiveSite {
+            name: name.to_string(),
+            host: host.to_string(),
+            kind: kind,
+        });
+    }
+
+    fn find(&self, host: &str) -> Option<&ArchiveSite> {
+        let host = if host.starts_with("www.") { &host[4..] } else { host };
+        self.sites.iter().find(|site| site.host == host)
+    }
+
+    pub fn identify(&self, url: &url::Url) -> Option<GlobalRef> {
+        let host = match url.domain() {
+            Some(host) => host,
+            None => return None,
+        };
+        let site = match self.find(host) {
+            Some(site) => site,
+            None => return None,
+        };
+        let path = match url.path() {
+            Some(path) if 0 < path.len() && path[0] != "" => path,
+            _ => return None,
+        };
+        archive_target(site.kind, path, url.fragment.as_ref()).map(|target| GlobalRef {
+            board: path[0].clone(),
+            target: target,
+            host: Some(host.to_string()),
+            origin: Some(site.name.clone()),
+            state: RefState::Live,
+        })
+    }
+}
+
+impl Default for ArchiveSiteRegistry {
+    fn default() -> Self {
+        ArchiveSiteRegistry::new()
+    }
+}
@@ -0,0 +1,88 @@
+use super::{Node, ArchiveSiteRegistry};
+use super::runs;
+use super::dehtml;
+
+const SCHEMES: [&'static str; 4] = ["https://", "http://", "ftp://", "magnet:?"];
+
+fn find_scheme(text: &str) -> Option<(usize, &'static str)> {
+    let mut best = None;
+    for &scheme in SCHEMES.iter() {
+        if let Some(idx) = text.find(scheme) {
+            match best {
+                Some((best_idx, _)) if best_idx <= idx => (),
+                _ => best = Some((idx, scheme)),
+            }
+        }
+    }
+    best
+}
+
+fn is_url_end(ch: char) -> bool {
+    ch.is_whitespace() || ch == '<' || ch == '>' || ch == '"'
+}
+
+// sentence punctuation directly after a link is not part of it
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let last = match url.chars().last() {
+            Some(last) => last,
+            None => return url,
+        };
+        let unbalanced = last == ')' && url.matches('(').count() < url.matches(')').count();
+        if ".,;:!?'".contains(last) || unbalanced {
+            url = &url[..url.len() - 1];
+        } else {
+            return url;
+        }
+    }
+}
+
+fn find_links(text: &str) -> Vec<(usize, usize)> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while let Some((start, scheme)) = find_scheme(&text[offset..]) {
+        let start = offset + start;
+        offset = start + scheme.len();
+
+        // xhttp://, not a link
+        if let Some(prev) = text[..start].chars().last() {
+            if prev.is_alphanumeric() {
+                continue;
+            }
+        }
+        let len = text[start..].find(is_url_end).unwrap_or(text.len() - start);
+        let url = trim_url(&text[start..start + len]);
+        if scheme.len() < url.len() {
+            out.push((start, start + url.len()));
+            offset = start + url.len();
+        }
+    }
+    out
+}
+
+fn link_run(archives: &ArchiveSiteRegistry, run: &[Node], out: &mut Vec<Node>) {
+    let text = runs::run_text(run);
+    let mut pos = 0;
+    for (start, end) in find_links(&text).into_iter() {
+        let url = &text[start..end];
+        let href = match dehtml::parse_href(url) {
+            Ok(href) => href,
+            // left in place, to be copied along with the text around it
+            Err(_) => continue,
+        };
+        runs::copy_range(run, pos, start, out);
+        match archives.identify(&href) {
+            Some(gref) => out.push(Node::GlobalRef(gref)),
+            None => out.push(Node::Anchor(href.serialize(), Box::new(Node::Text(url.to_string())))),
+        }
+        pos = end;
+    }
+    runs::copy_range(run, pos, text.len(), out);
+}
+
+// 4chan breaks long words up with <wbr>, so a pasted URL usually arrives
+// in several pieces; runs of text are rejoined before looking for links.
+pub fn autolink(node: &Node, archives: &ArchiveSiteRegistry) -> Node {
+    runs::map_runs(node, &|run: &[Node], out: &mut Vec<Node>| link_run(archives, run, out))
+}
@@ -0,0 +1,409 @@
+use serde::de::{self, Deserialize, Deserializer, Visitor, SeqVisitor};
+
+use super::{Node, EncapKind, LocalRef, GlobalRef, GlobalRefTarget, SearchQuery, RefState, PostContext};
+use super::tags;
+
+// A tag, written as its name or, in the compact encodings, its index in the
+// tags table.
+struct Tag(String);
+
+struct TagVisitor;
+
+impl Visitor for TagVisitor {
+    type Value = Tag;
+
+    fn visit_str<E>(&mut self, value: &str) -> Result<Tag, E>
+        where E: de::Error,
+    {
+        Ok(Tag(value.to_string()))
+    }
+
+    fn visit_u64<E>(&mut self, value: u64) -> Result<Tag, E>
+        where E: de::Error,
+    {
+        match tags::name(value) {
+            Some(name) => Ok(Tag(name.to_string())),
+            None => Err(de::Error::unknown_variant(&value.to_string())),
+        }
+    }
+}
+
+impl Deserialize for Tag {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Tag, D::Error>
+        where D: Deserializer,
+    {
+        deserializer.deserialize(TagVisitor)
+    }
+}
+
+fn next<V, T>(visitor: &mut V) -> Result<T, V::Error>
+    where V: SeqVisitor, T: Deserialize,
+{
+    match try!(visitor.visit()) {
+        Some(value) => Ok(value),
+        None => Err(de::Error::end_of_stream()),
+    }
+}
+
+fn expect_tag<V>(visitor: &mut V, expected: &str) -> Result<(), V::Error>
+    where V: SeqVisitor,
+{
+    let Tag(tag) = try!(next(visitor));
+    if tag != expected {
+        return Err(de::Error::unknown_variant(&tag));
+    }
+    Ok(())
+}
+
+// [post_id] or [post_id, board, thread, containing_post_id]
+fn visit_local_ref<V>(visitor: &mut V) -> Result<LocalRef, V::Error>
+    where V: SeqVisitor,
+{
+    let post = try!(next(visitor));
+    let context = match try!(visitor.visit()) {
+        Some(board) => {
+            let thread = try!(next(visitor));
+            let containing = try!(next(visitor));
+            Some(PostContext {
+                board: board,
+                thread: thread,
+                post: containing,
+            })
+        },
+        None => None,
+    };
+    Ok(LocalRef {
+        post: post,
+        state: RefState::Live,
+        context: context,
+    })
+}
+
+fn visit_target<V>(tag: &str, visitor: &mut V) -> Result<GlobalRefTarget, V::Error>
+    where V: SeqVisitor,
+{
+    let target = match tag {
+        "board" => GlobalRefTarget::Board,
+        "catalog" => GlobalRefTarget::Catalog,
+        "thread" => GlobalRefTarget::Thread(try!(next(visitor))),
+        "post" => {
+            let thread = try!(next(visitor));
+            let post = try!(next(visitor));
+            GlobalRefTarget::Post(thread, post)
+        },
+        "search" => {
+            let raw: String = try!(next(visitor));
+            GlobalRefTarget::Search(SearchQuery::new(&raw))
+        },
+        "archive" => GlobalRefTarget::Archive,
+        "page" => GlobalRefTarget::Page(try!(next(visitor))),
+        "rule" => GlobalRefTarget::Rule(try!(next(visitor))),
+        "unresolved" => GlobalRefTarget::Unresolved(try!(next(visitor))),
+        _ => return Err(de::Error::unknown_variant(tag)),
+    };
+    Ok(target)
+}
+
+// [target, board, ...]
+fn visit_global_ref<V>(visitor: &mut V) -> Result<GlobalRef, V::Error>
+    where V: SeqVisitor,
+{
+    let Tag(tag) = try!(next(visitor));
+    let board = try!(next(visitor));
+    let target = try!(visit_target(&tag, visitor));
+    Ok(GlobalRef {
+        board: board,
+        target: target,
+        host: None,
+        origin: None,
+        state: RefState::Live,
+    })
+}
+
+struct EncapKindVisitor;
+
+impl Visitor for EncapKindVisitor {
+    type Value = EncapKind;
+
+    fn visit_str<E>(&mut self, value: &str) -> Result<EncapKind, E>
+        where E: de::Error,
+    {
+        EncapKind::from_name(value).ok_or_else(|| de::Error::unknown_variant(value))
+    }
+}
+
+impl Deserialize for EncapKind {
+    fn deserialize<D>(deserializer: &mut D) -> Result<EncapKind, D::Error>
+        where D: Deserializer,
+    {
+        deserializer.deserialize_str(EncapKindVisitor)
+    }
+}
+
+struct LocalRefVisitor;
+
+impl Visitor for LocalRefVisitor {
+    type Value = LocalRef;
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<LocalRef, V::Error>
+        where V: SeqVisitor,
+    {
+        try!(expect_tag(&mut visitor, "localref"));
+        let lref = try!(visit_local_ref(&mut visitor));
+        try!(visitor.end());
+        Ok(lref)
+    }
+}
+
+impl Deserialize for LocalRef {
+    fn deserialize<D>(deserializer: &mut D) -> Result<LocalRef, D::Error>
+        where D: Deserializer,
+    {
+        deserializer.deserialize_seq(LocalRefVisitor)
+    }
+}
+
+struct GlobalRefVisitor;
+
+impl Visitor for GlobalRefVisitor {
+    type Value = GlobalRef;
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<GlobalRef, V::Error>
+        where V: SeqVisitor,
+    {
+        try!(expect_tag(&mut visitor, "globalref"));
+        let gref = try!(visit_global_ref(&mut visitor));
+        try!(visitor.end());
+        Ok(gref)
+    }
+}
+
+impl Deserialize for GlobalRef {
+    fn deserialize<D>(deserializer: &mut D) -> Result<GlobalRef, D::Error>
+        where D: Deserializer,
+    {
+        deserializer.deserialize_seq(GlobalRefVisitor)
+    }
+}
+
+struct GlobalRefTargetVisitor;
+
+impl Visitor for GlobalRefTargetVisitor {
+    type Value = GlobalRefTarget;
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<GlobalRefTarget, V::Error>
+        where V: SeqVisitor,
+    {
+        let Tag(tag) = try!(next(&mut visitor));
+        let target = try!(visit_target(&tag, &mut visitor));
+        try!(visitor.end());
+        Ok(target)
+    }
+}
+
+impl Deserialize for GlobalRefTarget {
+    fn deserialize<D>(deserializer: &mut D) -> Result<GlobalRefTarget, D::Error>
+        where D: Deserializer,
+    {
+        deserializer.deserialize_seq(GlobalRefTargetVisitor)
+    }
+}
+
+struct NodeVisitor;
+
+impl Visitor for NodeVisitor {
+    type Value = Node;
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<Node, V::Error>
+        where V: SeqVisitor,
+    {
+        let Tag(tag) = try!(next(&mut visitor));
+        let node = match &tag[..] {
+            "list" => Node::List(try!(next(&mut visitor))),
+            "localref" => Node::LocalRef(try!(visit_local_ref(&mut visitor))),
+            "globalref" => Node::GlobalRef(try!(visit_global_ref(&mut visitor))),
+            "archived" => {
+                let origin = try!(next(&mut visitor));
+                match try!(next(&mut visitor)) {
+                    Node::GlobalRef(mut gref) => {
+                        gref.origin = Some(origin);
+                        Node::GlobalRef(gref)
+                    },
+                    _ => return Err(de::Error::invalid_value("archived node is not a globalref")),
+                }
+            },
+            "dead" => match try!(next(&mut visitor)) {
+                Node::LocalRef(mut lref) => {
+                    lref.state = RefState::Dead;
+                    Node::LocalRef(lref)
+                },
+                Node::GlobalRef(mut gref) => {
+                    gref.state = RefState::Dead;
+                    Node::GlobalRef(gref)
+                },
+                _ => return Err(de::Error::invalid_value("dead node is not a reference")),
+            },
+            "text" => Node::Text(try!(next(&mut visitor))),
+            "anchor" => {
+                let url = try!(next(&mut visitor));
+                Node::Anchor(url, try!(next(&mut visitor)))
+            },
+            "unknown" => {
+                let tag = try!(next(&mut visitor));
+                let attrs = try!(next(&mut visitor));
+                Node::Unknown {
+                    tag: tag,
+                    attrs: attrs,
+                    children: try!(next(&mut visitor)),
+                }
+            },
+            "math" => Node::Math { display: false, tex: try!(next(&mut visitor)) },
+            "eqn" => Node::Math { display: true, tex: try!(next(&mut visitor)) },
+            "fortune" => {
+                let color = try!(next(&mut visitor));
+                Node::Fortune {
+                    color: color,
+                    text: try!(next(&mut visitor)),
+                }
+            },
+            "dice" => {
+                let rolls = try!(next(&mut visitor));
+                let total = try!(next(&mut visitor));
+                Node::DiceRoll {
+                    rolls: rolls,
+                    total: total,
+                    spec: try!(next(&mut visitor)),
+                }
+            },
+            "truncated" => {
+                let thread = try!(next(&mut visitor));
+                Node::Truncated {
+                    thread: thread,
+                    post: try!(next(&mut visitor)),
+                }
+            },
+            "exif" => Node::Exif(try!(next(&mut visitor))),
+            "styled" => {
+                let color = try!(next(&mut visitor));
+                let weight = try!(next(&mut visitor));
+                let size = try!(next(&mut visitor));
+                Node::Styled {
+                    color: color,
+                    weight: weight,
+                    size: size,
+                    children: try!(next(&mut visitor)),
+                }
+            },
+            "paragraph" => Node::Paragraph(try!(next(&mut visitor))),
+            "listblock" => {
+                let ordered = try!(next(&mut visitor));
+                Node::ListBlock {
+                    ordered: ordered,
+                    items: try!(next(&mut visitor)),
+                }
+            },
+            "heading" => {
+                let level = try!(next(&mut visitor));
+                Node::Heading(level, try!(next(&mut visitor)))
+            },
+            "hr" => Node::Rule,
+            "br" => Node::LineBreak,
+            "wbr" => Node::WordBreak,
+            _ => match EncapKind::from_name(&tag) {
+                // [kind, subnode]
+                Some(kind) => Node::Encap(kind, try!(next(&mut visitor))),
+                None => return Err(de::Error::unknown_variant(&tag)),
+            },
+        };
+        try!(visitor.end());
+        Ok(node)
+    }
+}
+
+impl Deserialize for Node {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Node, D::Error>
+        where D: Deserializer,
+    {
+        deserializer.deserialize_seq(NodeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    const WIRE_SAMPLES: &'static [&'static str] = &[
+        r#"["text","hello"]"#,
+        r#"["list",[["quote",["text",">implying"]],["br"],["text","no"]]]"#,
+        r#"["list",[["localref",123],["text"," "],["localref",456,"g",400,789]]]"#,
+        r#"["globalref","post","a",123,456]"#,
+        r#"["globalref","search","g","rust lang"]"#,
+        r#"["globalref","rule","global",11]"#,
+        r#"["dead",["globalref","unresolved","a",123]]"#,
+        r#"["dead",["localref",99]]"#,
+        r#"["archived","desuarchive",["globalref","thread","a",123]]"#,
+        r#"["anchor","https://boards.4chan.org/wsr/",["text","/wsr/"]]"#,
+        r#"["unknown","p",[["class","x"]],["text","t"]]"#,
+        r#"["list",[["math","x^2"],["eqn","\\int f"]]]"#,
+        r##"["fortune","#ff4500","Bad Luck"]"##,
+        r#"["dice",[3,5],8,"2d6"]"#,
+        r#"["dice",[4],4,null]"#,
+        r#"["truncated",123,456]"#,
+        r#"["exif",[["Equipment Make","Canon"]]]"#,
+        r#"["styled","red",null,"4",["bold",["text","x"]]]"#,
+        r#"["list",[["paragraph",["text","a"]],["listblock",true,[["text","1"]]],["heading",2,["text","h"]],["hr"],["wbr"]]]"#,
+    ];
+
+    #[test]
+    fn deserialize_round_trip() {
+        use serde_json::{from_str, to_string};
+        use super::Node;
+
+        for sample in WIRE_SAMPLES.iter() {
+            let node: Node = from_str(sample).unwrap();
+            assert_eq!(&to_string(&node).unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn deserialize_refs_round_trip() {
+        use serde_json::{from_str, to_string};
+        use super::{Node, LocalRef, GlobalRef, GlobalRefTarget};
+
+        let lref: LocalRef = from_str(r#"["localref",123]"#).unwrap();
+        assert_eq!(to_string(&lref).unwrap(), r#"["localref",123]"#);
+        let gref: GlobalRef = from_str(r#"["globalref","thread","g",123]"#).unwrap();
+        assert_eq!(to_string(&gref).unwrap(), r#"["globalref","thread","g",123]"#);
+        let target: GlobalRefTarget = from_str(r#"["post",1,2]"#).unwrap();
+        assert_eq!(to_string(&target).unwrap(), r#"["post",1,2]"#);
+
+        assert!(from_str::<Node>(r#"["nonsense",1]"#).is_err());
+    }
+
+    #[test]
+    fn parsed_round_trip() {
+        use serde_json::{from_str, to_string};
+        use super::Node;
+
+        let html = "<a href=\"#p123\" class=\"quotelink\">&gt;&gt;123</a><br>\
+            <span class=\"quote\">&gt;be me</span><br><s>spoiler</s>";
+        let node = super::super::parse_html(html).unwrap();
+        let buf = to_string(&node).unwrap();
+        let loaded: Node = from_str(&buf).unwrap();
+        assert_eq!(to_string(&loaded).unwrap(), buf);
+        assert_eq!(loaded, node);
+    }
+
+    #[test]
+    fn interned_tags_round_trip() {
+        use serde_json::{from_str, to_string};
+        use super::Node;
+        use super::super::ser::{Tagged, TagStyle};
+
+        for sample in WIRE_SAMPLES.iter() {
+            let node: Node = from_str(sample).unwrap();
+            let interned = to_string(&Tagged(&node, TagStyle::Interned)).unwrap();
+            assert!(interned.len() < sample.len());
+            let loaded: Node = from_str(&interned).unwrap();
+            assert_eq!(&to_string(&loaded).unwrap(), sample);
+        }
+    }
+}
@@ -6,7 +6,7 @@ use html5ever::Attribute;
 use html5ever::rcdom::{Document, Doctype, Text, Comment, Element, Handle};
 
 use super::Node as SNode;
-use super::{LocalRef, GlobalRef, GlobalRefTarget, EncapKind};
+use super::{LocalRef, GlobalRef, GlobalRefTarget, SearchQuery, RefState, EncapKind, ParseOptions};
 
 #[derive(Clone, Debug)]
 pub enum Node {
@@ -18,6 +18,38 @@ pub enum Node {
     LineBreak,
     WordBreakOpportunity,
     Anchor(String, Box<Node>),
+    Unknown {
+        tag: String,
+        attrs: Vec<(String, String)>,
+        children: Box<Node>,
+    },
+    Fortune {
+        color: Option<String>,
+        text: String,
+    },
+    DiceRoll {
+        rolls: Vec<u32>,
+        total: u32,
+        spec: Option<String>,
+    },
+    Truncated {
+        thread: u64,
+        post: u64,
+    },
+    Exif(Vec<(String, String)>),
+    Styled {
+        color: Option<String>,
+        weight: Option<String>,
+        size: Option<String>,
+        children: Box<Node>,
+    },
+    Paragraph(Box<Node>),
+    ListBlock {
+        ordered: bool,
+        items: Vec<Node>,
+    },
+    Heading(u8, Box<Node>),
+    Rule,
 }
 
 #[derive(Debug)]
@@ -43,13 +75,50 @@ impl Node {
             Node::LocalRef(ref lr) => SNode::LocalRef(lr.clone()),
             Node::GlobalRef(ref gr) => SNode::GlobalRef(gr.clone()),
             Node::Text(ref buf) => SNode::Text(buf.to_string()),
-            Node::LineBreak => SNode::Text("\n".to_string()),
-            Node::WordBreakOpportunity => SNode::Text(String::new()),
+            Node::LineBreak => SNode::LineBreak,
+            Node::WordBreakOpportunity => SNode::WordBreak,
             Node::Anchor(ref url, ref child) => SNode::Anchor(url.clone(), Box::new(child.to_super())),
+            Node::Unknown { ref tag, ref attrs, ref children } => SNode::Unknown {
+                tag: tag.clone(),
+                attrs: attrs.clone(),
+                children: Box::new(children.to_super()),
+            },
+            Node::Fortune { ref color, ref text } => SNode::Fortune {
+                color: color.clone(),
+                text: text.clone(),
+            },
+            Node::DiceRoll { ref rolls, total, ref spec } => SNode::DiceRoll {
+                rolls: rolls.clone(),
+                total: total,
+                spec: spec.clone(),
+            },
+            Node::Truncated { thread, post } => SNode::Truncated {
+                thread: thread,
+                post: post,
+            },
+            Node::Exif(ref rows) => SNode::Exif(rows.clone()),
+            Node::Styled { ref color, ref weight, ref size, ref children } => SNode::Styled {
+                color: color.clone(),
+                weight: weight.clone(),
+                size: size.clone(),
+                children: Box::new(children.to_super()),
+            },
+            Node::Paragraph(ref n) => SNode::Paragraph(Box::new(n.to_super())),
+            Node::ListBlock { ordered, ref items } => SNode::ListBlock {
+                ordered: ordered,
+                items: items.iter().map(|x| x.to_super()).collect(),
+            },
+            Node::Heading(level, ref n) => SNode::Heading(level, Box::new(n.to_super())),
+            Node::Rule => SNode::Rule,
         }
     }
 }
 
+struct Context<'a> {
+    options: &'a ParseOptions,
+    warnings: Vec<Error>,
+}
+
 fn find_body(handles: &[Handle]) -> Option<&Handle> {
     use html5ever::rcdom::ElementEnum::Normal;
 
@@ -65,65 +134,283 @@ fn find_body(handles: &[Handle]) -> Option<&Handle> {
     None
 }
 
-fn identify(handles: &[Handle]) -> Result<Node, Error> {
+fn identify(cx: &mut Context, handles: &[Handle]) -> Result<Node, Error> {
     let mut output = Vec::new();
     for handle in handles.iter() {
-        if let Some(node) = try!(identify_handle(handle)) {
+        if let Some(node) = try!(identify_handle(cx, handle)) {
             output.push(node);
         }
     }
     Ok(Node::List(output))
 }
 
+// In strict mode `err` is returned as-is; otherwise it is recorded as a
+// warning and the element is kept as an opaque Unknown node.
+fn identify_unknown(
+    cx: &mut Context,
+    tag: &str,
+    attrs: &[Attribute],
+    children: &[Handle],
+    err: Error,
+) -> Result<Node, Error> {
+    if cx.options.strict {
+        return Err(err);
+    }
+    cx.warnings.push(err);
+    let attrs = attrs.iter()
+        .map(|attr| (attr.name.local.as_ref().to_string(), attr.value.as_ref().to_string()))
+        .collect();
+    let children = try!(identify(cx, children));
+    Ok(Node::Unknown {
+        tag: tag.to_string(),
+        attrs: attrs,
+        children: Box::new(children),
+    })
+}
+
+fn text_content(handles: &[Handle]) -> String {
+    let mut out = String::new();
+    for handle in handles.iter() {
+        let node = handle.borrow();
+        match node.node {
+            Text(ref text) => out.push_str(text),
+            Element(_, _, _) => out.push_str(&text_content(&node.children)),
+            _ => (),
+        }
+    }
+    out
+}
+
+fn style_property(style: &str, name: &str) -> Option<String> {
+    for decl in style.split(';') {
+        let parts: Vec<&str> = decl.splitn(2, ':').collect();
+        if parts.len() == 2 && parts[0].trim().to_lowercase() == name {
+            return Some(parts[1].trim().to_string());
+        }
+    }
+    None
+}
+
 struct Span {
     classes: HashSet<String>,
+    style: Option<String>,
 }
 
 impl Span {
     pub fn from_attrs(attrs: &[Attribute]) -> Self {
         let mut classes: HashSet<String> = Default::default();
+        let mut style: Option<String> = None;
         for attr in attrs.iter() {
             let key = attr.name.local.as_ref();
             if "class" == key {
                 classes.extend(attr.value.as_ref().split(' ').map(|x| x.to_string()));
             }
+            if "style" == key {
+                style = Some(attr.value.as_ref().to_string());
+            }
+        }
+        Span {
+            classes: classes,
+            style: style,
         }
-        Span { classes: classes }
+    }
+}
+
+fn identify_span_fortune(span: &Span, children: &[Handle]) -> Node {
+    // <span class="fortune" style="color:#ff4500"><br><br><b>Your fortune: Bad Luck</b></span>
+    let text = text_content(children);
+    let text = text.trim();
+    let text = if text.starts_with("Your fortune:") {
+        text["Your fortune:".len()..].trim()
+    } else {
+        text
+    };
+    Node::Fortune {
+        color: span.style.as_ref().and_then(|style| style_property(style, "color")),
+        text: text.to_string(),
     }
 }
 
 fn identify_span_deadlink(text: &str) -> Result<Node, Error> {
+    // >>123, >>>/a/123, >>>/g/
     let err = || { Error::BadDeadlink(text.to_string()) };
-    let parts: Vec<&str> = text.splitn(3, "/").collect();
+    let text = text.trim();
+
+    if text.starts_with(">>>/") {
+        let parts: Vec<&str> = text[4..].splitn(2, '/').collect();
+        if parts.len() != 2 || parts[0].len() == 0 {
+            return Err(err());
+        }
+        let post = parts[1].trim_right_matches('/');
+        let target = if post.len() == 0 {
+            GlobalRefTarget::Board
+        } else {
+            GlobalRefTarget::Unresolved(try!(post.parse().map_err(|_| err())))
+        };
+        return Ok(Node::GlobalRef(GlobalRef {
+            board: parts[0].to_string(),
+            target: target,
+            host: None,
+            origin: None,
+            state: RefState::Dead,
+        }));
+    }
+    if text.starts_with(">>") {
+        let post = try!(text[2..].parse().map_err(|_| err()));
+        // a dead >>123 may have pointed into another thread, so the
+        // containing post's context would claim a thread it isn't known to be in
+        return Ok(Node::LocalRef(LocalRef {
+            post: post,
+            state: RefState::Dead,
+            context: None,
+        }));
+    }
+    Err(err())
+}
+
+fn find_href(handles: &[Handle]) -> Option<String> {
+    use html5ever::rcdom::ElementEnum::Normal;
 
-    if parts.len() == 1 {
-        let post_id = try!(text[2..].parse().map_err(|_| err()));
-        return Ok(Node::LocalRef(LocalRef { post: post_id }))
+    for handle in handles.iter() {
+        let node = handle.borrow();
+        if let Element(ref name, Normal, ref attrs) = node.node {
+            if name.local.as_ref() == "a" {
+                return Anchor::from_attrs(attrs).map(|anchor| anchor.href);
+            }
+        }
     }
-    if parts.len() < 2 || 3 < parts.len() {
-        return Err(Error::BadDeadlink(text.to_string()));
+    None
+}
+
+fn parse_truncated_href(href: &str) -> Result<(u64, u64), Error> {
+    // thread/123#p456, /g/thread/123#p456, res/123.html#p456
+    let parts: Vec<&str> = href.splitn(2, '#').collect();
+    let segments: Vec<&str> = parts[0].split('/').collect();
+    let pos = try!(segments.iter()
+        .position(|seg| *seg == "thread" || *seg == "res")
+        .ok_or(Error::BadRef));
+    let thread = try!(segments.get(pos + 1).ok_or(Error::BadRef));
+    let thread = thread.trim_right_matches(".html");
+    let thread = try!(thread.parse().map_err(|_| Error::BadRef));
+    let post = match parts.get(1) {
+        Some(frag) if frag.starts_with("p") => {
+            try!(frag[1..].parse().map_err(|_| Error::BadRef))
+        },
+        Some(_) => return Err(Error::BadRef),
+        None => thread,
+    };
+    Ok((thread, post))
+}
+
+fn identify_span_abbr(cx: &mut Context, attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
+    // <span class="abbr">Comment too long. <a href="thread/123#p456">Click here</a> to view the full text.</span>
+    if text_content(children).starts_with("Comment too long") {
+        let href = find_href(children).ok_or(Error::BadAnchor);
+        return match href.and_then(|href| parse_truncated_href(&href)) {
+            Ok((thread, post)) => Ok(Node::Truncated {
+                thread: thread,
+                post: post,
+            }),
+            Err(err) => identify_unknown(cx, "span", attrs, children, err),
+        };
     }
-    if parts[0] != ">>>" && parts[0] != ">>" {
-        return Err(Error::BadDeadlink(text.to_string()));
+    if text_content(children).starts_with("[EXIF data available") {
+        // the toggle link for the exif table that follows
+        return Ok(Node::List(Vec::new()));
     }
-    if parts.len() == 3 {
-        let post_id = try!(parts[2].parse().map_err(|_| err()));
-        Ok(Node::GlobalRef(GlobalRef {
-            board: parts[1].to_string(),
-            target: GlobalRefTarget::Thread(post_id),
-        }))
+    identify_unknown(cx, "span", attrs, children, Error::Unhandled)
+}
+
+fn is_ascii_letter(ch: char) -> bool {
+    ch.is_alphabetic() && (ch as u32) < 128
+}
+
+fn is_color(value: &str) -> bool {
+    // #f00, #ff4500, red
+    if value.starts_with("#") {
+        let hex = &value[1..];
+        (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|ch| ch.is_digit(16))
     } else {
-        let post_id = try!(parts[2].parse().map_err(|_| err()));
-        Ok(Node::LocalRef(LocalRef { post: post_id }))
+        0 < value.len() && value.chars().all(is_ascii_letter)
     }
 }
 
-fn identify_span(attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
+fn is_size(value: &str) -> bool {
+    // 12px, 1.5em, 120%, +2
+    let value = value.trim_left_matches(|ch| ch == '+' || ch == '-');
+    let unit = value.trim_left_matches(|ch: char| ch.is_digit(10) || ch == '.');
+    let known_unit = unit == "%" || (unit.len() <= 3 && unit.chars().all(is_ascii_letter));
+    unit.len() < value.len() && known_unit
+}
+
+// Values end up inside [color=...] and [size=...], so anything that isn't
+// plainly a color or a size is dropped.
+fn checked(value: Option<String>, valid: fn(&str) -> bool) -> Option<String> {
+    value.and_then(|value| if valid(&value) { Some(value) } else { None })
+}
+
+// the subset of inline CSS we keep, anything else is dropped
+struct Style {
+    color: Option<String>,
+    weight: Option<String>,
+    size: Option<String>,
+}
+
+impl Style {
+    pub fn from_css(css: &str) -> Option<Self> {
+        let style = Style {
+            color: checked(style_property(css, "color"), is_color),
+            weight: style_property(css, "font-weight"),
+            size: checked(style_property(css, "font-size"), is_size),
+        };
+        if style.color.is_none() && style.weight.is_none() && style.size.is_none() {
+            return None;
+        }
+        Some(style)
+    }
+
+    pub fn from_font_attrs(attrs: &[Attribute]) -> Self {
+        let mut style = Style {
+            color: None,
+            weight: None,
+            size: None,
+        };
+        for attr in attrs.iter() {
+            let key = attr.name.local.as_ref();
+            if "color" == key {
+                style.color = checked(Some(attr.value.as_ref().to_string()), is_color);
+            }
+            if "size" == key {
+                style.size = checked(Some(attr.value.as_ref().to_string()), is_size);
+            }
+        }
+        style
+    }
+}
+
+fn identify_styled(cx: &mut Context, style: Style, children: &[Handle]) -> Result<Node, Error> {
+    let children = try!(identify(cx, children));
+    Ok(Node::Styled {
+        color: style.color,
+        weight: style.weight,
+        size: style.size,
+        children: Box::new(children),
+    })
+}
+
+fn identify_span(cx: &mut Context, attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
     let span = Span::from_attrs(attrs);
 
     if span.classes.contains("quote") {
-        identify(children).map(|n|
+        identify(cx, children).map(|n|
             Node::Encap(EncapKind::Quote, Box::new(n)))
+    } else if span.classes.contains("sjis") {
+        identify(cx, children).map(|n|
+            Node::Encap(EncapKind::Sjis, Box::new(n)))
+    } else if span.classes.contains("fortune") {
+        Ok(identify_span_fortune(&span, children))
+    } else if span.classes.contains("abbr") {
+        identify_span_abbr(cx, attrs, children)
     } else if span.classes.contains("deadlink") {
         let child_text_res = children.get(0).ok_or(Error::Unhandled).and_then(|handle| {
             let node = handle.borrow();
@@ -132,11 +419,15 @@ fn identify_span(attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error
                 _ => Err(Error::Unhandled),
             }
         });
-        let child_text = try!(child_text_res);
-        identify_span_deadlink(&child_text)
+        match child_text_res.and_then(|text| identify_span_deadlink(&text)) {
+            Ok(node) => Ok(node),
+            Err(err) => identify_unknown(cx, "span", attrs, children, err),
+        }
+    } else if let Some(style) = span.style.as_ref().and_then(|css| Style::from_css(css)) {
+        // <span style="color: #0000ff; font-weight: bold;">
+        identify_styled(cx, style, children)
     } else {
-        println!("span.classes = {:?}", span.classes);
-        Err(Error::UnknownClassSet(span.classes))
+        identify_unknown(cx, "span", attrs, children, Error::UnknownClassSet(span.classes))
     }
 }
 
@@ -165,28 +456,39 @@ impl Anchor {
     }
 }
 
-fn identify_ref(anchor: Anchor) -> Result<Node, Error> {
-    if anchor.href.starts_with("//") {
-        let base = url::Url::parse("https://example.com").unwrap();
-        let mut parser = url::UrlParser::new();
-        parser.base_url(&base);
-
-        let url = try!(parser.parse(&anchor.href).map_err(|err| {
-            println!("err = {:?}", err);
-            Error::BadRef
-        }));
-        if anchor.href.len() % 5 < 5 {
-            // fix the FIX ME 
-            return Err(Error::Unhandled);
-        }
-        return Ok(Node::Anchor(anchor.href.into(), Box::new(Node::Text("FIX ME".into()))))
-    }
-    if anchor.href.starts_with("#") {
-        let post = try!(anchor.href[2..].parse().map_err(|_| Error::BadRef));
-        return Ok(Node::LocalRef(LocalRef { post: post }))
+fn identify_rule_ref(url: &url::Url, host: &str) -> Result<Node, Error> {
+    // //www.4chan.org/rules#global16, //www.4chan.org/rules#a1, rules#a2/
+    let frag = try!(url.fragment.as_ref().ok_or(Error::BadRef));
+    let frag = frag.trim_right_matches('/');
+    let split = try!(frag.char_indices().rev()
+        .take_while(|&(_, ch)| ch.is_digit(10))
+        .last()
+        .map(|(idx, _)| idx)
+        .ok_or(Error::BadRef));
+    if split == 0 {
+        return Err(Error::BadRef);
     }
+    let rule = try!(frag[split..].parse().map_err(|_| Error::BadRef));
+    Ok(Node::GlobalRef(GlobalRef {
+        board: frag[..split].to_string(),
+        target: GlobalRefTarget::Rule(rule),
+        host: Some(host.to_string()),
+        origin: None,
+        state: RefState::Live,
+    }))
+}
+
+fn decode_search(term: &str) -> String {
+    // catalog#s=rust%20lang, catalog#s=rust+lang
+    let term = term.replace("+", " ");
+    url::percent_encoding::lossy_utf8_percent_decode(term.as_bytes())
+}
 
-    let (path, midl, frag) = try!(url::parse_path(&anchor.href).map_err(|_| Error::BadRef));
+fn identify_board_path(
+    path: &[String],
+    frag: Option<String>,
+    host: Option<String>,
+) -> Result<Node, Error> {
     if path.len() < 2 {
         return Err(Error::BadRef);
     }
@@ -198,13 +500,19 @@ fn identify_ref(anchor: Anchor) -> Result<Node, Error> {
         if frag.len() == 0 {
             GlobalRefTarget::Catalog
         } else if frag.starts_with("s=") {
-            GlobalRefTarget::Search(frag[2..].to_string())
+            GlobalRefTarget::Search(SearchQuery::new(&decode_search(&frag[2..])))
         } else {
             return Err(Error::BadRef);
         }
+    } else if path[1] == "archive" {
+        GlobalRefTarget::Archive
+    } else if let Ok(page) = path[1].parse() {
+        // /g/2
+        GlobalRefTarget::Page(page)
     } else if path[1] == "thread" {
+        // anything after the thread number is an SEO slug
         let thread_num = match path.get(2) {
-            Some(ref part) if 1 < part.len() => {
+            Some(ref part) if 0 < part.len() => {
                 let thread_num = try!(part.parse().map_err(|_| Error::BadRef));
                 Some(thread_num)
             },
@@ -229,52 +537,242 @@ fn identify_ref(anchor: Anchor) -> Result<Node, Error> {
     Ok(Node::GlobalRef(GlobalRef {
         board: path[0].clone(),
         target: target,
+        host: host,
+        origin: None,
+        state: RefState::Live,
     }))
 }
 
-fn identify_anchor(attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
-    let anchor = try!(Anchor::from_attrs(attrs).ok_or(Error::BadAnchor));
+fn identify_absolute_ref(href: &str) -> Result<Node, Error> {
+    // https://boards.4channel.org/g/thread/123/slug#p456, //boards.4chan.org/a/catalog#s=foo
+    let base = url::Url::parse("https://boards.4chan.org/").unwrap();
+    let mut parser = url::UrlParser::new();
+    parser.base_url(&base);
+
+    let url = try!(parser.parse(href).map_err(|_| Error::BadRef));
+    let host = try!(url.domain().ok_or(Error::BadRef)).to_string();
+    let path = try!(url.path().ok_or(Error::BadRef));
+    match &host[..] {
+        "www.4chan.org" | "4chan.org" | "www.4channel.org" | "4channel.org" => {
+            if path.len() == 1 && path[0] == "rules" {
+                identify_rule_ref(&url, &host)
+            } else {
+                Err(Error::BadRef)
+            }
+        },
+        "boards.4chan.org" | "boards.4channel.org" => {
+            identify_board_path(path, url.fragment.clone(), Some(host.clone()))
+        },
+        _ => Err(Error::BadRef),
+    }
+}
+
+fn local_ref(cx: &Context, post: u64) -> LocalRef {
+    LocalRef {
+        post: post,
+        state: RefState::Live,
+        context: cx.options.context.clone(),
+    }
+}
+
+fn identify_ref(cx: &mut Context, anchor: Anchor) -> Result<Node, Error> {
+    if anchor.href.starts_with("//") || anchor.href.contains("://") {
+        return identify_absolute_ref(&anchor.href);
+    }
+    if anchor.href.starts_with("#") {
+        let post = try!(anchor.href[2..].parse().map_err(|_| Error::BadRef));
+        return Ok(Node::LocalRef(local_ref(cx, post)))
+    }
+
+    let (path, _query, frag) = try!(url::parse_path(&anchor.href).map_err(|_| Error::BadRef));
+    identify_board_path(&path, frag, None)
+}
+
+// Also used to normalize the links found by autolinking.
+pub fn parse_href(href: &str) -> Result<url::Url, Error> {
+    // relative and protocol-relative links are resolved against the boards
+    let base = url::Url::parse("https://boards.4chan.org/").unwrap();
+    let mut parser = url::UrlParser::new();
+    parser.base_url(&base);
+
+    parser.parse(href).map_err(|_| Error::BadAnchor)
+}
+
+fn identify_anchor(cx: &mut Context, attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
+    let anchor = match Anchor::from_attrs(attrs) {
+        Some(anchor) => anchor,
+        None => return identify_unknown(cx, "a", attrs, children, Error::BadAnchor),
+    };
     if anchor.classes.contains("quotelink") {
-        identify_ref(anchor)
+        match identify_ref(cx, anchor) {
+            Ok(node) => Ok(node),
+            Err(err) => identify_unknown(cx, "a", attrs, children, err),
+        }
     } else {
-        identify(children)
+        let url = match parse_href(&anchor.href) {
+            Ok(url) => url,
+            // an unparseable href is dropped, the link text is kept
+            Err(_) => return identify(cx, children),
+        };
+        if let Some(gref) = cx.options.archives.identify(&url) {
+            return Ok(Node::GlobalRef(gref));
+        }
+        let children = try!(identify(cx, children));
+        Ok(Node::Anchor(url.serialize(), Box::new(children)))
     }
 }
 
-fn identify_strong(attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
+fn identify_strong(cx: &mut Context, attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
     // <strong style=\"color: red;\">(USER WAS BANNED FOR THIS POST)</strong>
     for attr in attrs.iter() {
         if attr.name.local.as_ref() == "style" {
             if attr.value.as_ref() == "color: red;" {
-                let children = try!(identify(children));
+                let children = try!(identify(cx, children));
                 return Ok(Node::Encap(EncapKind::Ban, Box::new(children)));
             }
         }
     }
-    Err(Error::Unhandled)
+    identify(cx, children).map(|n| Node::Encap(EncapKind::Bold, Box::new(n)))
 }
 
-fn identify_document(handle: &Handle) -> Result<Option<Node>, Error> {
+fn parse_dice(text: &str) -> Option<Node> {
+    // Rolled 3, 5 = 8 (2d6)
+    let text = text.trim();
+    if !text.starts_with("Rolled ") {
+        return None;
+    }
+    let rest = &text["Rolled ".len()..];
+    let (rest, spec) = match rest.find(" (") {
+        Some(idx) if rest.ends_with(")") => {
+            (&rest[..idx], Some(rest[idx + 2..rest.len() - 1].to_string()))
+        },
+        _ => (rest, None),
+    };
+    let (rolls, total) = match rest.find(" = ") {
+        Some(idx) => (&rest[..idx], Some(&rest[idx + 3..])),
+        None => (rest, None),
+    };
+    let rolls: Vec<u32> = match rolls.split(',').map(|r| r.trim().parse()).collect() {
+        Ok(rolls) => rolls,
+        Err(_) => return None,
+    };
+    let total = match total {
+        Some(total) => match total.trim().parse() {
+            Ok(total) => total,
+            Err(_) => return None,
+        },
+        // a total that doesn't fit isn't a roll 4chan would have made
+        None => match rolls.iter().fold(Some(0u32), |acc, &roll| {
+            acc.and_then(|acc| acc.checked_add(roll))
+        }) {
+            Some(total) => total,
+            None => return None,
+        },
+    };
+    Some(Node::DiceRoll {
+        rolls: rolls,
+        total: total,
+        spec: spec,
+    })
+}
+
+fn identify_bold(cx: &mut Context, children: &[Handle]) -> Result<Node, Error> {
+    match parse_dice(&text_content(children)) {
+        Some(roll) => Ok(roll),
+        None => identify(cx, children).map(|n| Node::Encap(EncapKind::Bold, Box::new(n))),
+    }
+}
+
+fn collect_exif_rows(handles: &[Handle], rows: &mut Vec<(String, String)>) {
+    for handle in handles.iter() {
+        let node = handle.borrow();
+        match node.node {
+            Element(ref name, _, _) if name.local.as_ref() == "tr" => {
+                let cells: Vec<String> = node.children.iter()
+                    .filter(|cell| match cell.borrow().node {
+                        Element(ref name, _, _) => name.local.as_ref() == "td",
+                        _ => false,
+                    })
+                    .map(|cell| text_content(&cell.borrow().children).trim().to_string())
+                    .collect();
+                // single-cell rows are section headings
+                if cells.len() == 2 && cells[0].len() > 0 {
+                    rows.push((cells[0].clone(), cells[1].clone()));
+                }
+            },
+            // <tbody> is inserted by the parser
+            Element(_, _, _) => collect_exif_rows(&node.children, rows),
+            _ => (),
+        }
+    }
+}
+
+fn identify_table(cx: &mut Context, attrs: &[Attribute], children: &[Handle]) -> Result<Node, Error> {
+    // <table class="exif" id="exif123"><tr><td>Equipment Make</td><td>Canon</td></tr></table>
+    if Span::from_attrs(attrs).classes.contains("exif") {
+        let mut rows = Vec::new();
+        collect_exif_rows(children, &mut rows);
+        return Ok(Node::Exif(rows));
+    }
+    identify_unknown(cx, "table", attrs, children, Error::Unhandled)
+}
+
+fn identify_list_block(cx: &mut Context, ordered: bool, children: &[Handle]) -> Result<Node, Error> {
+    let mut items = Vec::new();
+    for handle in children.iter() {
+        let node = handle.borrow();
+        match node.node {
+            Element(ref name, _, _) if name.local.as_ref() == "li" => {
+                items.push(try!(identify(cx, &node.children)));
+            },
+            // indentation between the <li> elements
+            Text(ref text) if text.trim().len() == 0 => (),
+            _ => {
+                if let Some(item) = try!(identify_handle(cx, handle)) {
+                    items.push(item);
+                }
+            },
+        }
+    }
+    Ok(Node::ListBlock {
+        ordered: ordered,
+        items: items,
+    })
+}
+
+fn heading_level(tag: &str) -> Option<u8> {
+    match tag {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+fn identify_document(cx: &mut Context, handle: &Handle) -> Result<Option<Node>, Error> {
     let node = handle.borrow();
     let html = try!(node.children.get(0).ok_or(Error::BadDocument));
     let html = html.borrow();
 
     find_body(&html.children)
         .ok_or(Error::NoBody)
-        .and_then(identify_handle)
+        .and_then(|body| identify_handle(cx, body))
 }
 
-fn identify_handle(handle: &Handle) -> Result<Option<Node>, Error> {
+fn identify_handle(cx: &mut Context, handle: &Handle) -> Result<Option<Node>, Error> {
     use html5ever::rcdom::ElementEnum::Normal;
     let node = handle.borrow();
 
     match node.node {
-        Document => identify_document(handle),
+        Document => identify_document(cx, handle),
         Doctype(_, _, _) => Ok(None),
         Text(ref text) => Ok(Some(Node::Text(From::from(text)))),
         Comment(_) => Ok(None),
         Element(ref name, Normal, _) if name.local.as_ref() == "body" => {
-            identify(&node.children).map(Some)
+            identify(cx, &node.children).map(Some)
         },
         Element(ref name, Normal, _) if name.local.as_ref() == "wbr" => {
             Ok(Some(Node::WordBreakOpportunity))
@@ -283,22 +781,289 @@ fn identify_handle(handle: &Handle) -> Result<Option<Node>, Error> {
             Ok(Some(Node::LineBreak))
         },
         Element(ref name, Normal, ref attrs) if name.local.as_ref() == "span" => {
-            identify_span(&attrs, &node.children).map(Some)
+            identify_span(cx, &attrs, &node.children).map(Some)
         },
         Element(ref name, Normal, _) if name.local.as_ref() == "s" => {
-            identify(&node.children)
+            identify(cx, &node.children)
                 .map(|n| Some(Node::Encap(EncapKind::Spoiler, Box::new(n))))
         },
+        Element(ref name, Normal, _) if name.local.as_ref() == "i" => {
+            identify(cx, &node.children)
+                .map(|n| Some(Node::Encap(EncapKind::Italic, Box::new(n))))
+        },
+        Element(ref name, Normal, _) if name.local.as_ref() == "u" => {
+            identify(cx, &node.children)
+                .map(|n| Some(Node::Encap(EncapKind::Underline, Box::new(n))))
+        },
+        Element(ref name, Normal, _) if name.local.as_ref() == "pre" => {
+            // <pre class="prettyprint">int main() {<br>  return 0;<br>}</pre>
+            identify(cx, &node.children)
+                .map(|n| Some(Node::Encap(EncapKind::Code, Box::new(n))))
+        },
         Element(ref name, Normal, ref attrs) if name.local.as_ref() == "a" => {
-            identify_anchor(&attrs, &node.children).map(Some)
+            identify_anchor(cx, &attrs, &node.children).map(Some)
+        },
+        Element(ref name, Normal, _) if name.local.as_ref() == "hr" => {
+            Ok(Some(Node::Rule))
+        },
+        Element(ref name, Normal, _) if name.local.as_ref() == "p" || name.local.as_ref() == "div" => {
+            identify(cx, &node.children)
+                .map(|n| Some(Node::Paragraph(Box::new(n))))
+        },
+        Element(ref name, Normal, _) if name.local.as_ref() == "ul" || name.local.as_ref() == "ol" => {
+            let ordered = name.local.as_ref() == "ol";
+            identify_list_block(cx, ordered, &node.children).map(Some)
+        },
+        Element(ref name, Normal, _) if heading_level(name.local.as_ref()).is_some() => {
+            let level = heading_level(name.local.as_ref()).unwrap();
+            identify(cx, &node.children)
+                .map(|n| Some(Node::Heading(level, Box::new(n))))
+        },
+        Element(ref name, Normal, ref attrs) if name.local.as_ref() == "font" => {
+            // <font color="red" size="4">
+            identify_styled(cx, Style::from_font_attrs(&attrs), &node.children).map(Some)
+        },
+        Element(ref name, Normal, ref attrs) if name.local.as_ref() == "table" => {
+            identify_table(cx, &attrs, &node.children).map(Some)
+        },
+        Element(ref name, Normal, _) if name.local.as_ref() == "b" => {
+            identify_bold(cx, &node.children).map(Some)
         },
         Element(ref name, Normal, ref attrs) if name.local.as_ref() == "strong" => {
-            identify_strong(&attrs, &node.children).map(Some)
+            identify_strong(cx, &attrs, &node.children).map(Some)
+        },
+        Element(ref name, _, ref attrs) => {
+            identify_unknown(cx, name.local.as_ref(), &attrs, &node.children, Error::Unhandled)
+                .map(Some)
         },
-        Element(_, _, _) => Err(Error::Unhandled),
     }
 }
 
-pub fn load_html(node: &[Handle]) -> Result<super::Node, Error> {
-    identify(node).map(|n| n.to_super())
+pub fn load_html(node: &[Handle], options: &ParseOptions) -> Result<(super::Node, Vec<Error>), Error> {
+    let mut cx = Context {
+        options: options,
+        warnings: Vec::new(),
+    };
+    let node = try!(identify(&mut cx, node));
+    Ok((node.to_super(), cx.warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::to_string;
+
+    use super::Error;
+    use super::super::{parse_html, parse_html_with, ParseOptions, ArchiveSiteRegistry};
+    use super::super::{Node, GlobalRef, GlobalRefTarget, PostContext, parse_html_in_context};
+
+    fn parse(html: &str) -> String {
+        to_string(&parse_html(html).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn deadlinks() {
+        assert_eq!(parse("<span class=\"deadlink\">&gt;&gt;123</span>"),
+            r#"["dead",["localref",123]]"#);
+        assert_eq!(parse("<span class=\"deadlink\">&gt;&gt;&gt;/g/</span>"),
+            r#"["dead",["globalref","board","g"]]"#);
+        assert_eq!(parse("<span class=\"deadlink\">&gt;&gt;&gt;/a/123</span>"),
+            r#"["dead",["globalref","unresolved","a",123]]"#);
+
+        let html = "<span class=\"deadlink\">&gt;&gt;abc</span>";
+        match parse_html(html) {
+            Err(Error::BadDeadlink(ref text)) if text == ">>abc" => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let lenient = ParseOptions { strict: false, ..Default::default() };
+        let parsed = parse_html_with(html, &lenient).unwrap();
+        assert_eq!(to_string(&parsed.node).unwrap(),
+            r#"["unknown","span",[["class","deadlink"]],["text",">>abc"]]"#);
+        assert_eq!(parsed.warnings.len(), 1);
+    }
+
+    #[test]
+    fn lenient_anchors() {
+        let lenient = ParseOptions { strict: false, ..Default::default() };
+        let cases = [
+            ("<a href=\"#pxyz\" class=\"quotelink\">&gt;&gt;xyz</a>",
+                r##"["unknown","a",[["href","#pxyz"],["class","quotelink"]],["text",">>xyz"]]"##),
+            ("<a>nowhere</a>", r#"["unknown","a",[],["text","nowhere"]]"#),
+            ("<span class=\"abbr\">Comment too long. <a href=\"thread/abc\">Click here</a></span>",
+                concat!(r#"["unknown","span",[["class","abbr"]],"#,
+                    r#"["list",[["text","Comment too long. "],["anchor","https://boards.4chan.org/thread/abc",["text","Click here"]]]]]"#)),
+        ];
+        for &(html, expected) in cases.iter() {
+            assert!(parse_html(html).is_err());
+            let parsed = parse_html_with(html, &lenient).unwrap();
+            assert_eq!(to_string(&parsed.node).unwrap(), expected);
+            assert_eq!(parsed.warnings.len(), 1);
+        }
+    }
+
+    #[test]
+    fn in_context() {
+        let cx = PostContext { board: "g".to_string(), thread: 400, post: 789 };
+        let local_ref = |html: &str| match parse_html_in_context(html, &cx).unwrap() {
+            Node::LocalRef(lref) => lref,
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let op = local_ref("<a href=\"#p400\" class=\"quotelink\">&gt;&gt;400</a>");
+        assert_eq!((op.board(), op.thread()), (Some("g"), Some(400)));
+        assert!(op.is_op() && !op.is_self());
+
+        let own = local_ref("<a href=\"#p789\" class=\"quotelink\">&gt;&gt;789</a>");
+        assert!(!own.is_op() && own.is_self());
+
+        let dead = local_ref("<span class=\"deadlink\">&gt;&gt;400</span>");
+        assert_eq!((dead.board(), dead.thread()), (None, None));
+        assert!(!dead.is_op() && !dead.is_self());
+    }
+
+    #[test]
+    fn anchors() {
+        assert_eq!(parse("<a href=\"//boards.4chan.org/wsr/\">/wsr/</a>"),
+            r#"["anchor","https://boards.4chan.org/wsr/",["text","/wsr/"]]"#);
+        assert_eq!(parse("<a href=\"http://[::1\">link</a>"), r#"["text","link"]"#);
+    }
+
+    #[test]
+    fn autolinks() {
+        let options = ParseOptions { autolink: true, ..Default::default() };
+        let html = "see http://Example.COM/a<wbr>/b, ok";
+        let parsed = parse_html_with(html, &options).unwrap();
+        assert_eq!(to_string(&parsed.node).unwrap(),
+            r#"["list",[["text","see "],["anchor","http://example.com/a/b",["text","http://Example.COM/a/b"]],["text",", ok"]]]"#);
+        let breaks = ParseOptions { autolink: true, keep_breaks: true, ..Default::default() };
+        let parsed = parse_html_with("see <wbr>http://[::1 ok", &breaks).unwrap();
+        assert_eq!(to_string(&parsed.node).unwrap(),
+            r#"["list",[["text","see "],["wbr"],["text","http://[::1 ok"]]]"#);
+    }
+
+    #[test]
+    fn catalog_search() {
+        let html = "<a href=\"/g/catalog#s=rust%20Lang+lang\" class=\"quotelink\">x</a>";
+        assert_eq!(parse(html), r#"["globalref","search","g","rust Lang lang"]"#);
+        match parse_html(html).unwrap() {
+            Node::GlobalRef(GlobalRef { target: GlobalRefTarget::Search(ref query), .. }) => {
+                assert_eq!(query.raw(), "rust Lang lang");
+                assert_eq!(query.terms(), &["rust", "lang", "lang"]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn board_pages() {
+        let quotelink = |href: &str| parse(&format!("<a href=\"{}\" class=\"quotelink\">x</a>", href));
+        assert_eq!(quotelink("/g/archive"), r#"["globalref","archive","g"]"#);
+        assert_eq!(quotelink("/g/2"), r#"["globalref","page","g",2]"#);
+        assert_eq!(quotelink("/g/thread/123/some-slug"), r#"["globalref","thread","g",123]"#);
+        assert_eq!(quotelink("/g/thread/123/some-slug#p456"),
+            r#"["globalref","post","g",123,456]"#);
+        assert_eq!(quotelink("//boards.4channel.org/g/thread/123/some-slug#p456"),
+            r#"["globalref","post","g",123,456]"#);
+    }
+
+    #[test]
+    fn math() {
+        let options = ParseOptions { math: true, ..Default::default() };
+        let breaks = ParseOptions { math: true, keep_breaks: true, ..Default::default() };
+        let math = |html: &str, options: &ParseOptions| {
+            to_string(&parse_html_with(html, options).unwrap().node).unwrap()
+        };
+        assert_eq!(math("é [math]x +<br>y[/math] b [eqn]\\sum<br>i[/eqn]", &options),
+            r#"["list",[["text","é "],["math","x +\ny"],["text"," b "],["eqn","\\sum\ni"]]]"#);
+        assert_eq!(math("a [math]x<wbr>+y[/math]<wbr>b", &breaks),
+            r#"["list",[["text","a "],["math","x+y"],["wbr"],["text","b"]]]"#);
+        // unterminated, left as text with its breaks
+        assert_eq!(math("one [math]x<br>two [eqn]y", &breaks),
+            r#"["list",[["text","one [math]x"],["br"],["text","two [eqn]y"]]]"#);
+        assert_eq!(math("<pre class=\"prettyprint\">[math]x[/math]</pre> [math]y[/math]", &options),
+            r#"["list",[["code",["text","[math]x[/math]"]],["text"," "],["math","y"]]]"#);
+    }
+
+    #[test]
+    fn truncated() {
+        let abbr = |href: &str| parse(&format!(
+            "<span class=\"abbr\">Comment too long. <a href=\"{}\">Click here</a> to view the full text.</span>",
+            href));
+        assert_eq!(abbr("thread/123#p456"), r#"["truncated",123,456]"#);
+        assert_eq!(abbr("/g/thread/123#p456"), r#"["truncated",123,456]"#);
+        assert_eq!(abbr("res/123.html#p456"), r#"["truncated",123,456]"#);
+        assert_eq!(abbr("thread/123"), r#"["truncated",123,123]"#);
+
+        let html = "<span class=\"quote\">&gt;be me</span><br><b>wait \
+            <span class=\"abbr\">Comment too long. <a href=\"thread/123#p456\">Click here</a></span></b>";
+        assert!(parse_html(html).unwrap().is_truncated());
+        assert!(!parse_html("<span class=\"quote\">&gt;be me</span><br><b>wait</b>").unwrap().is_truncated());
+    }
+
+    #[test]
+    fn exif() {
+        let html = "photo<br><span class=\"abbr\">[EXIF data available. Click \
+            <a href=\"javascript:void(0)\" onclick=\"toggle('exif123')\">here</a> to show/hide.]</span><br>\
+            <table class=\"exif\" id=\"exif123\">\
+            <tr><td colspan=\"2\"><b>Camera-Specific Properties:</b></td></tr>\
+            <tr><td>Equipment Make</td><td>Canon</td></tr>\
+            <tr><td>Camera Model</td><td>Canon EOS 5D</td></tr></table>";
+        // the <tr>s end up in a parser-inserted <tbody>
+        let node = parse_html(html).unwrap();
+        assert_eq!(to_string(&node).unwrap(), concat!(
+            r#"["list",[["text","photo\n\n"],"#,
+            r#"["exif",[["Equipment Make","Canon"],["Camera Model","Canon EOS 5D"]]]]]"#));
+        assert_eq!(node.to_bbcode(), "photo\n\n");
+    }
+
+    #[test]
+    fn breaks() {
+        let html = "line<br>long<wbr>word";
+        assert_eq!(parse(html), r#"["text","line\nlongword"]"#);
+        let breaks = ParseOptions { keep_breaks: true, ..Default::default() };
+        assert_eq!(to_string(&parse_html_with(html, &breaks).unwrap().node).unwrap(),
+            r#"["list",[["text","line"],["br"],["text","long"],["wbr"],["text","word"]]]"#);
+    }
+
+    #[test]
+    fn styled() {
+        let html = "<span style=\"color: #ff4500; font-size: 120%\">hi</span>";
+        let node = parse_html(html).unwrap();
+        assert_eq!(to_string(&node).unwrap(), r##"["styled","#ff4500",null,"120%",["text","hi"]]"##);
+        assert_eq!(node.to_bbcode(), "[color=#ff4500][size=120%]hi[/size][/color]");
+
+        let html = "<font color=\"red]x[/color\" size=\"3][b\">hi</font> <font color=\"red\" size=\"+2\">ok</font>";
+        assert_eq!(parse_html(html).unwrap().to_bbcode(), "hi [color=red][size=+2]ok[/size][/color]");
+        let html = "<span style=\"color: red]x[/color; font-weight: bold\">hi</span>";
+        assert_eq!(parse_html(html).unwrap().to_bbcode(), "[b]hi[/b]");
+    }
+
+    #[test]
+    fn dice() {
+        assert_eq!(parse("<b>Rolled 3, 5 (2d6)</b>"), r#"["dice",[3,5],8,"2d6"]"#);
+        assert_eq!(parse("<b>Rolled 4294967295, 1 (2d4294967295)</b>"),
+            r#"["bold",["text","Rolled 4294967295, 1 (2d4294967295)"]]"#);
+    }
+
+    #[test]
+    fn archive_links() {
+        let html = "<a href=\"https://desuarchive.org/a/thread/123/#456\">link</a> \
+            https://warosu.org/ck/thread/123#p456 <a href=\"https://warosu.org/faq\">faq</a>";
+        let options = ParseOptions { autolink: true, ..Default::default() };
+        let parsed = parse_html_with(html, &options).unwrap();
+        assert_eq!(to_string(&parsed.node).unwrap(), concat!(
+            r#"["list",[["anchor","https://desuarchive.org/a/thread/123/#456",["text","link"]],"#,
+            r#"["text"," "],["anchor","https://warosu.org/ck/thread/123#p456",["text","https://warosu.org/ck/thread/123#p456"]],"#,
+            r#"["text"," "],["anchor","https://warosu.org/faq",["text","faq"]]]]"#));
+
+        let options = ParseOptions {
+            autolink: true,
+            archives: ArchiveSiteRegistry::known_sites(),
+            ..Default::default()
+        };
+        let parsed = parse_html_with(html, &options).unwrap();
+        assert_eq!(to_string(&parsed.node).unwrap(), concat!(
+            r#"["list",[["archived","desuarchive",["globalref","post","a",123,456]],"#,
+            r#"["text"," "],["archived","warosu",["globalref","post","ck",123,456]],"#,
+            r#"["text"," "],["anchor","https://warosu.org/faq",["text","faq"]]]]"#));
+    }
 }
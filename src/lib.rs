@@ -3,8 +3,13 @@ extern crate html5ever;
 extern crate tendril;
 extern crate serde;
 extern crate serde_json;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
+#[cfg(feature = "msgpack")]
+extern crate rmp;
 
 use std::default::Default;
+use std::hash::{Hash, Hasher};
 
 use tendril::stream::TendrilSink;
 
@@ -12,12 +17,27 @@ use html5ever::{parse_document};
 use html5ever::rcdom::RcDom;
 
 pub mod dehtml;
+pub mod archive;
+mod runs;
+mod math;
+mod autolink;
+mod tags;
+mod ser;
+mod de;
+mod object;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum EncapKind {
     Quote,
     Spoiler,
     Ban,
+    Code,
+    Sjis,
+    Bold,
+    Italic,
+    Underline,
 }
 
 impl EncapKind {
@@ -27,40 +47,272 @@ impl EncapKind {
             EK::Quote => "quote",
             EK::Spoiler => "spoiler",
             EK::Ban => "ban",
+            EK::Code => "code",
+            EK::Sjis => "sjis",
+            EK::Bold => "bold",
+            EK::Italic => "italic",
+            EK::Underline => "underline",
         }
     }
-}
 
-impl serde::Serialize for EncapKind {
-    #[inline]
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-        where S: serde::Serializer,
-    {
-        self.as_str().serialize(serializer)
+    pub fn from_name(name: &str) -> Option<EncapKind> {
+        use self::EncapKind as EK;
+        match name {
+            "quote" => Some(EK::Quote),
+            "spoiler" => Some(EK::Spoiler),
+            "ban" => Some(EK::Ban),
+            "code" => Some(EK::Code),
+            "sjis" => Some(EK::Sjis),
+            "bold" => Some(EK::Bold),
+            "italic" => Some(EK::Italic),
+            "underline" => Some(EK::Underline),
+            _ => None,
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RefState {
+    Live,
+    // rendered by 4chan as <span class="deadlink">
+    Dead,
+}
+
+pub use archive::{ArchiveKind, ArchiveSiteRegistry};
+
+#[derive(Clone, Debug)]
 pub struct LocalRef {
     post: u64,
+    state: RefState,
+    // the post the reference was found in, when parsed in context
+    context: Option<PostContext>,
+}
+
+// context says where the reference was found rather than what it points at,
+// so it does not take part in comparisons: the same copy-pasta posted in two
+// threads compares equal.
+impl PartialEq for LocalRef {
+    fn eq(&self, other: &LocalRef) -> bool {
+        self.post == other.post && self.state == other.state
+    }
+}
+
+impl Eq for LocalRef {}
+
+impl Hash for LocalRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.post.hash(state);
+        self.state.hash(state);
+    }
+}
+
+impl LocalRef {
+    pub fn new(post: u64) -> LocalRef {
+        LocalRef {
+            post: post,
+            state: RefState::Live,
+            context: None,
+        }
+    }
+
+    pub fn with_context(mut self, context: PostContext) -> LocalRef {
+        self.context = Some(context);
+        self
+    }
+
+    pub fn with_state(mut self, state: RefState) -> LocalRef {
+        self.state = state;
+        self
+    }
+
+    pub fn post(&self) -> u64 {
+        self.post
+    }
+
+    pub fn state(&self) -> RefState {
+        self.state
+    }
+
+    pub fn context(&self) -> Option<&PostContext> {
+        self.context.as_ref()
+    }
+
+    pub fn board(&self) -> Option<&str> {
+        self.context.as_ref().map(|cx| &cx.board[..])
+    }
+
+    pub fn thread(&self) -> Option<u64> {
+        self.context.as_ref().map(|cx| cx.thread)
+    }
+
+    pub fn is_op(&self) -> bool {
+        self.thread() == Some(self.post)
+    }
+
+    pub fn is_self(&self) -> bool {
+        self.context.as_ref().map_or(false, |cx| cx.post == self.post)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct GlobalRef {
     board: String,
     target: GlobalRefTarget,
+    // set for absolute links, e.g. "boards.4channel.org"; not serialized
+    host: Option<String>,
+    // the archive site name for links into an archive, e.g. "desuarchive"
+    origin: Option<String>,
+    state: RefState,
 }
 
-#[derive(Clone, Debug)]
+// host only records which domain an absolute link was written with, so it
+// does not take part in comparisons: >>>/g/1 is the same reference via
+// boards.4chan.org and boards.4channel.org.
+impl PartialEq for GlobalRef {
+    fn eq(&self, other: &GlobalRef) -> bool {
+        self.board == other.board &&
+            self.target == other.target &&
+            self.origin == other.origin &&
+            self.state == other.state
+    }
+}
+
+impl Eq for GlobalRef {}
+
+impl Hash for GlobalRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.board.hash(state);
+        self.target.hash(state);
+        self.origin.hash(state);
+        self.state.hash(state);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum GlobalRefTarget {
     Board,
     Catalog,
     Thread(u64),
     Post(u64, u64),
-    Search(String),
+    Search(SearchQuery),
+    Archive,
+    // numbered index page, /g/2
+    Page(u32),
+    // the board is the rule scope: "global" or the board name
+    Rule(u32),
+    // a post whose thread is not known, from dead cross-board refs
+    Unresolved(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SearchQuery {
+    // the decoded query as typed into the catalog search box
+    raw: String,
+    // lowercased words of the query, for grouping equivalent searches
+    terms: Vec<String>,
+}
+
+impl SearchQuery {
+    pub fn new(raw: &str) -> SearchQuery {
+        SearchQuery {
+            raw: raw.to_string(),
+            terms: raw.split_whitespace().map(|t| t.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn terms(&self) -> &[String] {
+        &self.terms
+    }
+}
+
+impl GlobalRefTarget {
+    pub fn board() -> GlobalRefTarget {
+        GlobalRefTarget::Board
+    }
+
+    pub fn catalog() -> GlobalRefTarget {
+        GlobalRefTarget::Catalog
+    }
+
+    pub fn thread(thread: u64) -> GlobalRefTarget {
+        GlobalRefTarget::Thread(thread)
+    }
+
+    pub fn post(thread: u64, post: u64) -> GlobalRefTarget {
+        GlobalRefTarget::Post(thread, post)
+    }
+
+    pub fn search(query: &str) -> GlobalRefTarget {
+        GlobalRefTarget::Search(SearchQuery::new(query))
+    }
+
+    pub fn archive() -> GlobalRefTarget {
+        GlobalRefTarget::Archive
+    }
+
+    pub fn page(page: u32) -> GlobalRefTarget {
+        GlobalRefTarget::Page(page)
+    }
+
+    pub fn rule(rule: u32) -> GlobalRefTarget {
+        GlobalRefTarget::Rule(rule)
+    }
+
+    pub fn unresolved(post: u64) -> GlobalRefTarget {
+        GlobalRefTarget::Unresolved(post)
+    }
 }
 
 impl GlobalRef {
+    pub fn new(board: &str, target: GlobalRefTarget) -> GlobalRef {
+        GlobalRef {
+            board: board.to_string(),
+            target: target,
+            host: None,
+            origin: None,
+            state: RefState::Live,
+        }
+    }
+
+    pub fn with_host(mut self, host: &str) -> GlobalRef {
+        self.host = Some(host.to_string());
+        self
+    }
+
+    pub fn with_origin(mut self, origin: &str) -> GlobalRef {
+        self.origin = Some(origin.to_string());
+        self
+    }
+
+    pub fn with_state(mut self, state: RefState) -> GlobalRef {
+        self.state = state;
+        self
+    }
+
+    pub fn board(&self) -> &str {
+        &self.board
+    }
+
+    pub fn target(&self) -> &GlobalRefTarget {
+        &self.target
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_ref().map(|host| &host[..])
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_ref().map(|origin| &origin[..])
+    }
+
+    pub fn state(&self) -> RefState {
+        self.state
+    }
+
     pub fn best_post(&self) -> Option<u64> {
         match self.target {
             GlobalRefTarget::Board => None,
@@ -68,11 +320,15 @@ impl GlobalRef {
             GlobalRefTarget::Thread(thn) => Some(thn),
             GlobalRefTarget::Post(_, pn) => Some(pn),
             GlobalRefTarget::Search(_) => None,
+            GlobalRefTarget::Archive => None,
+            GlobalRefTarget::Page(_) => None,
+            GlobalRefTarget::Rule(_) => None,
+            GlobalRefTarget::Unresolved(pn) => Some(pn),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Node {
     List(Vec<Node>),
     Encap(EncapKind, Box<Node>),
@@ -80,58 +336,50 @@ pub enum Node {
     GlobalRef(GlobalRef),
     Text(String),
     Anchor(String, Box<Node>),
+    Unknown {
+        tag: String,
+        attrs: Vec<(String, String)>,
+        children: Box<Node>,
+    },
+    Math {
+        // [eqn] is display math, [math] is inline
+        display: bool,
+        tex: String,
+    },
+    Fortune {
+        color: Option<String>,
+        text: String,
+    },
+    DiceRoll {
+        rolls: Vec<u32>,
+        total: u32,
+        spec: Option<String>,
+    },
+    // marks a comment cut short on an index page, the full text lives at
+    // thread/post
+    Truncated {
+        thread: u64,
+        post: u64,
+    },
+    // camera metadata from /p/, not part of the comment text
+    Exif(Vec<(String, String)>),
+    Styled {
+        color: Option<String>,
+        weight: Option<String>,
+        size: Option<String>,
+        children: Box<Node>,
+    },
+    Paragraph(Box<Node>),
+    ListBlock {
+        ordered: bool,
+        items: Vec<Node>,
+    },
+    Heading(u8, Box<Node>),
+    Rule,
+    LineBreak,
+    WordBreak,
 }
 
-impl serde::Serialize for Node {
-    #[inline]
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-        where S: serde::Serializer,
-    {
-        use serde::Serialize;
-        use self::Node as N;
-        match *self {
-            N::List(ref nodes) => {
-                // ['list', [nodes]]
-                // Ideally this would be [list,  *nodes] tho ...
-                ("list", nodes).serialize(serializer)
-            },
-            N::Encap(kind, ref subnode) => {
-                // [kind, subnode]
-                (kind, subnode).serialize(serializer)
-            },
-            N::LocalRef(ref lref) => {
-                // ['localref', post_id]
-                ("localref", lref.post).serialize(serializer)
-            },
-            N::GlobalRef(GlobalRef { ref board, ref target }) => match *target {
-                GlobalRefTarget::Board => {
-                    ("globalref", "board", board).serialize(serializer)
-                },
-                GlobalRefTarget::Catalog => {
-                    ("globalref", "catalog", board).serialize(serializer)
-                },
-                GlobalRefTarget::Thread(thread) => {
-                    ("globalref", "thread", board, thread).serialize(serializer)
-                },
-                GlobalRefTarget::Post(thread, post) => {
-                    ("globalref", "post", board, thread, post).serialize(serializer)
-                },
-                GlobalRefTarget::Search(ref term) => {
-                    ("globalref", "search", board, term).serialize(serializer)
-                }
-            },
-            N::Text(ref text) => {
-                // ['text', text]
-                ("text", text).serialize(serializer)
-            },
-            N::Anchor(ref url, ref child) => {
-                ("anchor", url, child).serialize(serializer)
-            }
-        }
-    }
-}
-
-
 fn list_optimize_helper(nodes: &[Node]) -> Vec<Node> {
     let mut out = Vec::new();
     for node in nodes.iter() {
@@ -165,9 +413,9 @@ fn merge_texts(nodes: &[Node]) -> Vec<Node> {
     out
 }
 
-fn list_optimize(nodes: &[Node]) -> Node {
+fn list_optimize(nodes: &[Node], merge: bool) -> Node {
     let out = list_optimize_helper(nodes);
-    let out = merge_texts(&out);
+    let out = if merge { merge_texts(&out) } else { out };
     let mut out = list_optimize_helper(&out);
     if out.len() == 1 {
         out.pop().unwrap()
@@ -178,21 +426,96 @@ fn list_optimize(nodes: &[Node]) -> Node {
 
 impl Node {
     pub fn optimize(&self) -> Node {
+        self.optimize_with(&Default::default())
+    }
+
+    pub fn optimize_with(&self, options: &ParseOptions) -> Node {
+        self.optimize_inner(options.keep_breaks, true)
+    }
+
+    // Equality up to optimize(): nested lists, split text runs and breaks
+    // folded into text don't count. To dedupe with a HashSet, insert the
+    // optimized trees.
+    pub fn semantic_eq(&self, other: &Node) -> bool {
+        self.optimize() == other.optimize()
+    }
+
+    fn optimize_inner(&self, keep_breaks: bool, merge: bool) -> Node {
+        let recurse = |node: &Node| node.optimize_inner(keep_breaks, merge);
         match *self {
             Node::List(ref children) => {
-                let children: Vec<_> = children.iter().map(Node::optimize).collect();
-                list_optimize(&children)
+                let children: Vec<_> = children.iter().map(recurse).collect();
+                list_optimize(&children, merge)
             }
+            Node::Encap(EncapKind::Sjis, ref child) => {
+                // shift-jis art is column-aligned, keep its text runs as parsed
+                Node::Encap(EncapKind::Sjis, Box::new(child.optimize_inner(keep_breaks, false)))
+            },
             Node::Encap(ref kind, ref child) => {
-                Node::Encap(*kind, Box::new(child.optimize()))
+                Node::Encap(*kind, Box::new(recurse(child)))
             },
             Node::LocalRef(ref lr) => Node::LocalRef(lr.clone()),
             Node::GlobalRef(ref gr) => Node::GlobalRef(gr.clone()),
             Node::Text(ref ss) => Node::Text(ss.clone()),
-            Node::Anchor(ref u, ref ch) => Node::Anchor(u.clone(), Box::new(ch.optimize())),
+            Node::Anchor(ref u, ref ch) => Node::Anchor(u.clone(), Box::new(recurse(ch))),
+            Node::Unknown { ref tag, ref attrs, ref children } => Node::Unknown {
+                tag: tag.clone(),
+                attrs: attrs.clone(),
+                children: Box::new(recurse(children)),
+            },
+            Node::Math { display, ref tex } => Node::Math { display: display, tex: tex.clone() },
+            Node::Fortune { .. } => self.clone(),
+            Node::DiceRoll { .. } => self.clone(),
+            Node::Truncated { .. } => self.clone(),
+            Node::Exif(ref rows) => Node::Exif(rows.clone()),
+            Node::Styled { ref color, ref weight, ref size, ref children } => Node::Styled {
+                color: color.clone(),
+                weight: weight.clone(),
+                size: size.clone(),
+                children: Box::new(recurse(children)),
+            },
+            Node::Paragraph(ref ch) => Node::Paragraph(Box::new(recurse(ch))),
+            Node::ListBlock { ordered, ref items } => Node::ListBlock {
+                ordered: ordered,
+                items: items.iter().map(recurse).collect(),
+            },
+            Node::Heading(level, ref ch) => Node::Heading(level, Box::new(recurse(ch))),
+            Node::Rule => Node::Rule,
+            Node::LineBreak if keep_breaks => Node::LineBreak,
+            Node::LineBreak => Node::Text("\n".to_string()),
+            Node::WordBreak if keep_breaks => Node::WordBreak,
+            Node::WordBreak => Node::Text(String::new()),
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        match *self {
+            Node::List(ref nodes) => nodes.iter().any(Node::is_truncated),
+            Node::Encap(_, ref child) => child.is_truncated(),
+            Node::Anchor(_, ref child) => child.is_truncated(),
+            Node::Unknown { ref children, .. } => children.is_truncated(),
+            Node::Styled { ref children, .. } => children.is_truncated(),
+            Node::Paragraph(ref child) => child.is_truncated(),
+            Node::ListBlock { ref items, .. } => items.iter().any(Node::is_truncated),
+            Node::Heading(_, ref child) => child.is_truncated(),
+            Node::Truncated { .. } => true,
+            _ => false,
         }
     }
 
+    pub fn extract_math(&self) -> Node {
+        math::extract_math(self)
+    }
+
+    pub fn autolink(&self) -> Node {
+        self.autolink_with(&ArchiveSiteRegistry::new())
+    }
+
+    // as autolink(), links into the given archives become GlobalRefs
+    pub fn autolink_with(&self, archives: &ArchiveSiteRegistry) -> Node {
+        autolink::autolink(self, archives)
+    }
+
     pub fn bbcode_fmt(&self, wri: &mut String) {
         use std::fmt::Write;
         use self::Node::*;
@@ -217,22 +540,139 @@ impl Node {
                 node.bbcode_fmt(wri);
                 write!(wri, "[/ban]").unwrap();
             },
+            Encap(EncapKind::Code, ref node) => {
+                write!(wri, "[code]").unwrap();
+                node.bbcode_fmt(wri);
+                write!(wri, "[/code]").unwrap();
+            },
+            Encap(EncapKind::Sjis, ref node) => {
+                write!(wri, "[sjis]").unwrap();
+                node.bbcode_fmt(wri);
+                write!(wri, "[/sjis]").unwrap();
+            },
+            Encap(EncapKind::Bold, ref node) => {
+                write!(wri, "[b]").unwrap();
+                node.bbcode_fmt(wri);
+                write!(wri, "[/b]").unwrap();
+            },
+            Encap(EncapKind::Italic, ref node) => {
+                write!(wri, "[i]").unwrap();
+                node.bbcode_fmt(wri);
+                write!(wri, "[/i]").unwrap();
+            },
+            Encap(EncapKind::Underline, ref node) => {
+                write!(wri, "[u]").unwrap();
+                node.bbcode_fmt(wri);
+                write!(wri, "[/u]").unwrap();
+            },
             LocalRef(ref po) => {
+                let dead = po.state == RefState::Dead;
+                if dead {
+                    write!(wri, "[s]").unwrap();
+                }
                 write!(wri, ">>{}", po.post).unwrap();
+                if dead {
+                    write!(wri, "[/s]").unwrap();
+                }
             },
             GlobalRef(ref gr) => {
-                match gr.best_post() {
-                    Some(bp) => write!(wri, ">>>/{}/{}", gr.board, bp).unwrap(),
-                    None => write!(wri, ">>>/{}/", gr.board).unwrap(),
+                let dead = gr.state == RefState::Dead;
+                if dead {
+                    write!(wri, "[s]").unwrap();
+                }
+                match (&gr.target, gr.best_post()) {
+                    (&GlobalRefTarget::Rule(rule), _) => {
+                        write!(wri, ">>>/{}/rules/{}", gr.board, rule).unwrap()
+                    },
+                    (_, Some(bp)) => write!(wri, ">>>/{}/{}", gr.board, bp).unwrap(),
+                    (_, None) => write!(wri, ">>>/{}/", gr.board).unwrap(),
+                }
+                if dead {
+                    write!(wri, "[/s]").unwrap();
                 }
-                
             },
             Text(ref text) => {
                 write!(wri, "{}", text).unwrap();
             },
-            Anchor(ref _url, ref text) => {
-                text.bbcode_fmt(wri)
+            Anchor(ref url, ref text) => {
+                write!(wri, "[url={}]", url).unwrap();
+                text.bbcode_fmt(wri);
+                write!(wri, "[/url]").unwrap();
+            },
+            Unknown { ref children, .. } => {
+                children.bbcode_fmt(wri)
+            },
+            Math { display: false, ref tex } => {
+                write!(wri, "[math]{}[/math]", tex).unwrap();
+            },
+            Math { display: true, ref tex } => {
+                write!(wri, "[eqn]{}[/eqn]", tex).unwrap();
+            },
+            Fortune { ref text, .. } => {
+                write!(wri, "Your fortune: {}", text).unwrap();
             },
+            DiceRoll { ref rolls, total, ref spec } => {
+                let rolls: Vec<String> = rolls.iter().map(|r| r.to_string()).collect();
+                write!(wri, "Rolled {} = {}", rolls.join(", "), total).unwrap();
+                if let Some(ref spec) = *spec {
+                    write!(wri, " ({})", spec).unwrap();
+                }
+            },
+            Truncated { .. } => (),
+            Exif(_) => (),
+            Styled { ref color, ref weight, ref size, ref children } => {
+                let bold = match weight.as_ref().map(|w| w.as_str()) {
+                    Some("bold") | Some("bolder") | Some("700") | Some("800") | Some("900") => true,
+                    _ => false,
+                };
+                if let Some(ref color) = *color {
+                    write!(wri, "[color={}]", color).unwrap();
+                }
+                if let Some(ref size) = *size {
+                    write!(wri, "[size={}]", size).unwrap();
+                }
+                if bold {
+                    write!(wri, "[b]").unwrap();
+                }
+                children.bbcode_fmt(wri);
+                if bold {
+                    write!(wri, "[/b]").unwrap();
+                }
+                if size.is_some() {
+                    write!(wri, "[/size]").unwrap();
+                }
+                if color.is_some() {
+                    write!(wri, "[/color]").unwrap();
+                }
+            },
+            Paragraph(ref node) => {
+                node.bbcode_fmt(wri);
+                write!(wri, "\n").unwrap();
+            },
+            ListBlock { ordered, ref items } => {
+                if ordered {
+                    write!(wri, "[list=1]").unwrap();
+                } else {
+                    write!(wri, "[list]").unwrap();
+                }
+                for item in items.iter() {
+                    write!(wri, "[*]").unwrap();
+                    item.bbcode_fmt(wri);
+                }
+                write!(wri, "[/list]").unwrap();
+            },
+            Heading(_, ref node) => {
+                write!(wri, "[b]").unwrap();
+                node.bbcode_fmt(wri);
+                write!(wri, "[/b]\n").unwrap();
+            },
+            Rule => {
+                write!(wri, "[hr]").unwrap();
+            },
+            LineBreak => {
+                write!(wri, "\n").unwrap();
+            },
+            WordBreak => (),
         }
     }
 
@@ -241,17 +681,141 @@ impl Node {
         self.bbcode_fmt(&mut output);
         output
     }
+
+    pub fn to_json_writer<W>(&self, wri: &mut W, format: OutputFormat) -> Result<(), serde_json::Error>
+        where W: std::io::Write,
+    {
+        match format {
+            OutputFormat::Tuple => serde_json::ser::to_writer(wri, self),
+            OutputFormat::Object => serde_json::ser::to_writer(wri, &object::to_value(self)),
+        }
+    }
+
+    pub fn to_json(&self, format: OutputFormat) -> String {
+        let mut output = Vec::new();
+        self.to_json_writer(&mut output, format).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    // The tuple format as CBOR, with tags written as small integers.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(&ser::Tagged(self, ser::TagStyle::Interned))
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(buf: &[u8]) -> Result<Node, serde_cbor::Error> {
+        serde_cbor::from_slice(buf)
+    }
+
+    // The tuple format as MessagePack, with tags written as small integers.
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, msgpack::Error> {
+        let mut output = Vec::new();
+        try!(msgpack::to_writer(&mut output, self));
+        Ok(output)
+    }
+
+    #[cfg(feature = "msgpack")]
+    pub fn from_msgpack(buf: &[u8]) -> Result<Node, msgpack::Error> {
+        msgpack::from_slice(buf)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    // ['globalref', 'post', board, thread, post]
+    Tuple,
+    // {"version": N, "root": {"type": "globalref", "target": "post", ...}}
+    Object,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Tuple
+    }
+}
+
+pub use object::SCHEMA_VERSION;
+
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    // when false, unrecognized markup becomes Node::Unknown plus a warning
+    // instead of failing the whole document
+    pub strict: bool,
+    // split [math] and [eqn] segments out of text nodes
+    pub math: bool,
+    // keep <br> and <wbr> as LineBreak and WordBreak instead of folding
+    // them into the surrounding text
+    pub keep_breaks: bool,
+    // wrap http, https, ftp and magnet URLs found in text in anchors
+    pub autolink: bool,
+    // the post being parsed, attached to every live LocalRef
+    pub context: Option<PostContext>,
+    // links into these sites become GlobalRefs instead of anchors; empty by
+    // default, ArchiveSiteRegistry::known_sites() has the common archives
+    pub archives: ArchiveSiteRegistry,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            strict: true,
+            math: false,
+            keep_breaks: false,
+            autolink: false,
+            context: None,
+            archives: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PostContext {
+    pub board: String,
+    pub thread: u64,
+    pub post: u64,
+}
+
+#[derive(Debug)]
+pub struct Parsed {
+    pub node: Node,
+    pub warnings: Vec<dehtml::Error>,
 }
 
 pub fn parse_html(buf: &str) -> Result<Node, dehtml::Error> {
+    parse_html_with(buf, &Default::default()).map(|parsed| parsed.node)
+}
+
+pub fn parse_html_in_context(buf: &str, context: &PostContext) -> Result<Node, dehtml::Error> {
+    let options = ParseOptions {
+        context: Some(context.clone()),
+        ..Default::default()
+    };
+    parse_html_with(buf, &options).map(|parsed| parsed.node)
+}
+
+pub fn parse_html_with(buf: &str, options: &ParseOptions) -> Result<Parsed, dehtml::Error> {
     let dom: RcDom = parse_document(RcDom::default(), Default::default()).one(buf);
-    let rv = dehtml::load_html(&[dom.document]);
+    let (node, warnings) = try!(dehtml::load_html(&[dom.document], options));
 
-    rv.map(|node| node.optimize())
+    let mut node = node.optimize_with(options);
+    if options.math {
+        node = node.extract_math();
+    }
+    if options.autolink {
+        node = node.autolink_with(&options.archives);
+    }
+    Ok(Parsed {
+        node: node,
+        warnings: warnings,
+    })
 }
 
 
-#[cfg(test)]
+// Dumps every post of a local ../comments file (posts separated by a pilcrow).
+// The file is not checked in; enable the `comments` feature to run these.
+#[cfg(all(test, feature = "comments"))]
 mod tests {
     const COMMENTS: &'static str = include_str!("../comments");
 
@@ -286,3 +850,156 @@ mod tests {
         panic!("zx");
     }
 }
+
+#[cfg(test)]
+mod node_tests {
+    #[test]
+    fn construct_refs() {
+        use serde_json::{from_str, to_string};
+        use super::{Node, LocalRef, GlobalRef, GlobalRefTarget, RefState, PostContext};
+
+        let cx = PostContext { board: "g".to_string(), thread: 400, post: 789 };
+        let lref = LocalRef::new(456).with_context(cx);
+        assert_eq!(lref.post(), 456);
+        assert_eq!(lref.board(), Some("g"));
+        assert_eq!(to_string(&lref).unwrap(), r#"["localref",456,"g",400,789]"#);
+
+        let gref = GlobalRef::new("a", GlobalRefTarget::post(123, 456));
+        assert_eq!(gref.board(), "a");
+        assert_eq!(*gref.target(), GlobalRefTarget::Post(123, 456));
+        assert_eq!(gref, from_str(r#"["globalref","post","a",123,456]"#).unwrap());
+
+        let search = GlobalRef::new("g", GlobalRefTarget::search("Rust Lang"));
+        match *search.target() {
+            GlobalRefTarget::Search(ref query) => assert_eq!(query.raw(), "Rust Lang"),
+            ref target => panic!("unexpected target {:?}", target),
+        }
+
+        let dead = Node::GlobalRef(GlobalRef::new("a", GlobalRefTarget::unresolved(123))
+            .with_state(RefState::Dead));
+        assert_eq!(to_string(&dead).unwrap(), r#"["dead",["globalref","unresolved","a",123]]"#);
+    }
+
+    #[test]
+    fn semantic_eq() {
+        use std::collections::HashSet;
+        use super::{Node, EncapKind};
+
+        let split = Node::List(vec![
+            Node::Text("copy".to_string()),
+            Node::List(vec![Node::Text("pasta".to_string())]),
+            Node::LineBreak,
+            Node::Encap(EncapKind::Quote, Box::new(Node::Text(">be me".to_string()))),
+        ]);
+        let joined = Node::List(vec![
+            Node::Text("copypasta\n".to_string()),
+            Node::Encap(EncapKind::Quote, Box::new(Node::Text(">be me".to_string()))),
+        ]);
+        assert!(split != joined);
+        assert!(split.semantic_eq(&joined));
+        assert!(!split.semantic_eq(&Node::Text("copypasta\n".to_string())));
+
+        let seen: HashSet<Node> = vec![split, joined].iter().map(|node| node.optimize()).collect();
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn global_ref_eq_ignores_host() {
+        use std::collections::HashSet;
+        use serde_json::{from_str, to_string};
+        use super::Node;
+
+        let html = "<a href=\"//boards.4chan.org/g/thread/1#p2\" class=\"quotelink\">&gt;&gt;&gt;/g/2</a>";
+        let chan = super::parse_html(html).unwrap();
+        let channel = super::parse_html(&html.replace("4chan.org", "4channel.org")).unwrap();
+        assert_eq!(chan, channel);
+
+        let loaded: Node = from_str(&to_string(&chan).unwrap()).unwrap();
+        assert_eq!(loaded, chan);
+
+        let seen: HashSet<Node> = vec![chan, channel, loaded].into_iter().collect();
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn local_ref_eq_ignores_context() {
+        use std::collections::HashSet;
+        use super::{Node, PostContext};
+
+        let html = "<a href=\"#p123\" class=\"quotelink\">&gt;&gt;123</a> same pasta";
+        let first = PostContext { board: "g".to_string(), thread: 100, post: 101 };
+        let second = PostContext { board: "a".to_string(), thread: 200, post: 201 };
+        let first = super::parse_html_in_context(html, &first).unwrap();
+        let second = super::parse_html_in_context(html, &second).unwrap();
+        assert_eq!(first, second);
+        assert!(first.semantic_eq(&second));
+
+        let seen: HashSet<Node> = vec![first, second].iter().map(|node| node.optimize()).collect();
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn blackbox_parses() {
+        use serde_json::from_str;
+
+        let lenient = super::ParseOptions { strict: false, ..Default::default() };
+        for line in include_str!("../blackbox/test.jsonl").lines().filter(|line| line.len() > 0) {
+            let html: String = from_str(line).unwrap();
+            if let Err(err) = super::parse_html(&html) {
+                panic!("err = {:?} for doc {:?}", err, html);
+            }
+            let parsed = super::parse_html_with(&html, &lenient).unwrap();
+            assert!(parsed.warnings.is_empty(), "warnings = {:?}", parsed.warnings);
+        }
+    }
+
+    #[cfg(any(feature = "cbor", feature = "msgpack"))]
+    fn blackbox_nodes() -> Vec<super::Node> {
+        use serde_json::from_str;
+
+        let options = super::ParseOptions { strict: false, ..Default::default() };
+        include_str!("../blackbox/test.jsonl").lines()
+            .filter(|line| line.len() > 0)
+            .map(|line| {
+                let html: String = from_str(line).unwrap();
+                super::parse_html_with(&html, &options).unwrap().node
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_smaller_than_json() {
+        use super::Node;
+
+        let (mut json_len, mut cbor_len) = (0, 0);
+        for node in blackbox_nodes().iter() {
+            let json = node.to_json(super::OutputFormat::Tuple);
+            let cbor = node.to_cbor().unwrap();
+            let loaded = Node::from_cbor(&cbor).unwrap();
+            assert_eq!(loaded.to_json(super::OutputFormat::Tuple), json);
+            json_len += json.len();
+            cbor_len += cbor.len();
+        }
+        println!("json = {} bytes, cbor = {} bytes", json_len, cbor_len);
+        assert!(cbor_len < json_len);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_smaller_than_json() {
+        use super::Node;
+
+        let (mut json_len, mut msgpack_len) = (0, 0);
+        for node in blackbox_nodes().iter() {
+            let json = node.to_json(super::OutputFormat::Tuple);
+            let msgpack = node.to_msgpack().unwrap();
+            let loaded = Node::from_msgpack(&msgpack).unwrap();
+            assert_eq!(loaded.to_json(super::OutputFormat::Tuple), json);
+            json_len += json.len();
+            msgpack_len += msgpack.len();
+        }
+        println!("json = {} bytes, msgpack = {} bytes", json_len, msgpack_len);
+        assert!(msgpack_len < json_len);
+    }
+}
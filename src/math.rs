@@ -0,0 +1,68 @@
+use super::Node;
+use super::runs;
+
+const DELIMITERS: [(&'static str, &'static str, bool); 2] = [
+    ("[math]", "[/math]", false),
+    ("[eqn]", "[/eqn]", true),
+];
+
+fn find_open(text: &str) -> Option<(usize, &'static str, &'static str, bool)> {
+    let mut best = None;
+    for &(open, close, display) in DELIMITERS.iter() {
+        if let Some(idx) = text.find(open) {
+            match best {
+                Some((best_idx, _, _, _)) if best_idx <= idx => (),
+                _ => best = Some((idx, open, close, display)),
+            }
+        }
+    }
+    best
+}
+
+struct Segment {
+    start: usize,
+    end: usize,
+    display: bool,
+    tex: String,
+}
+
+fn find_segments(text: &str) -> Vec<Segment> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while let Some((start, open, close, display)) = find_open(&text[offset..]) {
+        let tex_start = offset + start + open.len();
+        let tex_end = match text[tex_start..].find(close) {
+            Some(len) => tex_start + len,
+            // unterminated, leave the delimiter as text
+            None => break,
+        };
+        out.push(Segment {
+            start: offset + start,
+            end: tex_end + close.len(),
+            display: display,
+            tex: text[tex_start..tex_end].to_string(),
+        });
+        offset = tex_end + close.len();
+    }
+    out
+}
+
+fn split_run(run: &[Node], out: &mut Vec<Node>) {
+    let text = runs::run_text(run);
+    let mut pos = 0;
+    for segment in find_segments(&text).into_iter() {
+        runs::copy_range(run, pos, segment.start, out);
+        out.push(Node::Math {
+            display: segment.display,
+            tex: segment.tex,
+        });
+        pos = segment.end;
+    }
+    runs::copy_range(run, pos, text.len(), out);
+}
+
+// A segment may span several lines: runs of text, <br> and <wbr> are
+// scanned as one piece of text.
+pub fn extract_math(node: &Node) -> Node {
+    runs::map_runs(node, &split_run)
+}
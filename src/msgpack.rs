@@ -0,0 +1,182 @@
+use std::io::{self, Write};
+use std::string::FromUtf8Error;
+
+use rmp::Marker;
+use rmp::encode::{self, ValueWriteError};
+use serde_json::{self, Value};
+
+use super::Node;
+use super::ser::{Tagged, TagStyle};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // the buffer ends in the middle of a value
+    Truncated,
+    // bin, ext and reserved markers never appear in an encoded node
+    BadMarker(u8),
+    BadString(FromUtf8Error),
+    // map keys are always strings
+    BadKey,
+    BadNode(serde_json::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<ValueWriteError> for Error {
+    fn from(err: ValueWriteError) -> Error {
+        match err {
+            ValueWriteError::InvalidMarkerWrite(err) => Error::Io(err),
+            ValueWriteError::InvalidDataWrite(err) => Error::Io(err),
+        }
+    }
+}
+
+fn write_value<W>(wr: &mut W, value: &Value) -> Result<(), Error>
+    where W: Write,
+{
+    match *value {
+        Value::Null => try!(encode::write_nil(wr)),
+        Value::Bool(value) => try!(encode::write_bool(wr, value)),
+        Value::I64(value) => {
+            try!(encode::write_sint(wr, value));
+        },
+        Value::U64(value) => {
+            try!(encode::write_uint(wr, value));
+        },
+        Value::F64(value) => try!(encode::write_f64(wr, value)),
+        Value::String(ref value) => try!(encode::write_str(wr, value)),
+        Value::Array(ref items) => {
+            try!(encode::write_array_len(wr, items.len() as u32));
+            for item in items.iter() {
+                try!(write_value(wr, item));
+            }
+        },
+        Value::Object(ref map) => {
+            try!(encode::write_map_len(wr, map.len() as u32));
+            for (key, item) in map.iter() {
+                try!(encode::write_str(wr, key));
+                try!(write_value(wr, item));
+            }
+        },
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    // big-endian, as all msgpack lengths and numbers are
+    fn uint(&mut self, len: usize) -> Result<u64, Error> {
+        let bytes = try!(self.take(len));
+        Ok(bytes.iter().fold(0, |acc, &byte| acc << 8 | byte as u64))
+    }
+
+    fn string(&mut self, len: u64) -> Result<Value, Error> {
+        let bytes = try!(self.take(len as usize)).to_vec();
+        String::from_utf8(bytes).map(Value::String).map_err(Error::BadString)
+    }
+
+    fn array(&mut self, len: u64) -> Result<Value, Error> {
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(try!(self.value()));
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(&mut self, len: u64) -> Result<Value, Error> {
+        let mut map = ::std::collections::BTreeMap::new();
+        for _ in 0..len {
+            let key = match try!(self.value()) {
+                Value::String(key) => key,
+                _ => return Err(Error::BadKey),
+            };
+            map.insert(key, try!(self.value()));
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        let marker = Marker::from_u8(try!(self.uint(1)) as u8);
+        let value = match marker {
+            Marker::Null => Value::Null,
+            Marker::True => Value::Bool(true),
+            Marker::False => Value::Bool(false),
+            Marker::FixPos(value) => Value::U64(value as u64),
+            Marker::U8 => Value::U64(try!(self.uint(1))),
+            Marker::U16 => Value::U64(try!(self.uint(2))),
+            Marker::U32 => Value::U64(try!(self.uint(4))),
+            Marker::U64 => Value::U64(try!(self.uint(8))),
+            Marker::FixNeg(value) => Value::I64(value as i64),
+            Marker::I8 => Value::I64(try!(self.uint(1)) as i8 as i64),
+            Marker::I16 => Value::I64(try!(self.uint(2)) as i16 as i64),
+            Marker::I32 => Value::I64(try!(self.uint(4)) as i32 as i64),
+            Marker::I64 => Value::I64(try!(self.uint(8)) as i64),
+            Marker::F32 => Value::F64(f32::from_bits(try!(self.uint(4)) as u32) as f64),
+            Marker::F64 => Value::F64(f64::from_bits(try!(self.uint(8)))),
+            Marker::FixStr(len) => try!(self.string(len as u64)),
+            Marker::Str8 => {
+                let len = try!(self.uint(1));
+                try!(self.string(len))
+            },
+            Marker::Str16 => {
+                let len = try!(self.uint(2));
+                try!(self.string(len))
+            },
+            Marker::Str32 => {
+                let len = try!(self.uint(4));
+                try!(self.string(len))
+            },
+            Marker::FixArray(len) => try!(self.array(len as u64)),
+            Marker::Array16 => {
+                let len = try!(self.uint(2));
+                try!(self.array(len))
+            },
+            Marker::Array32 => {
+                let len = try!(self.uint(4));
+                try!(self.array(len))
+            },
+            Marker::FixMap(len) => try!(self.map(len as u64)),
+            Marker::Map16 => {
+                let len = try!(self.uint(2));
+                try!(self.map(len))
+            },
+            Marker::Map32 => {
+                let len = try!(self.uint(4));
+                try!(self.map(len))
+            },
+            marker => return Err(Error::BadMarker(marker.to_u8())),
+        };
+        Ok(value)
+    }
+}
+
+// The tuple format with tags written as their index in the tags table.
+pub fn to_writer<W>(wr: &mut W, node: &Node) -> Result<(), Error>
+    where W: Write,
+{
+    let value = serde_json::value::to_value(&Tagged(node, TagStyle::Interned));
+    write_value(wr, &value)
+}
+
+pub fn from_slice(buf: &[u8]) -> Result<Node, Error> {
+    let mut reader = Reader { buf: buf };
+    let value = try!(reader.value());
+    serde_json::value::from_value(value).map_err(Error::BadNode)
+}
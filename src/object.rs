@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use super::{Node, LocalRef, GlobalRef, GlobalRefTarget, RefState};
+
+// Bumped whenever a field is removed or changes meaning; adding fields is
+// not a breaking change.
+pub const SCHEMA_VERSION: u64 = 1;
+
+fn object(kind: &str, fields: Vec<(&'static str, Value)>) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("type".to_string(), Value::String(kind.to_string()));
+    for (key, value) in fields.into_iter() {
+        map.insert(key.to_string(), value);
+    }
+    Value::Object(map)
+}
+
+fn string(value: &str) -> Value {
+    Value::String(value.to_string())
+}
+
+fn opt_string(value: &Option<String>) -> Value {
+    match *value {
+        Some(ref value) => string(value),
+        None => Value::Null,
+    }
+}
+
+fn pairs(rows: &[(String, String)]) -> Value {
+    Value::Array(rows.iter()
+        .map(|&(ref key, ref value)| Value::Array(vec![string(key), string(value)]))
+        .collect())
+}
+
+fn nodes(nodes: &[Node]) -> Value {
+    Value::Array(nodes.iter().map(node_value).collect())
+}
+
+fn local_ref(lref: &LocalRef) -> Value {
+    let mut fields = vec![
+        ("post", Value::U64(lref.post)),
+        ("dead", Value::Bool(lref.state == RefState::Dead)),
+    ];
+    if let Some(ref cx) = lref.context {
+        fields.push(("board", string(&cx.board)));
+        fields.push(("thread", Value::U64(cx.thread)));
+        fields.push(("in_post", Value::U64(cx.post)));
+    }
+    object("localref", fields)
+}
+
+fn global_ref(gref: &GlobalRef) -> Value {
+    let mut fields = vec![
+        ("board", string(&gref.board)),
+        ("host", opt_string(&gref.host)),
+        ("origin", opt_string(&gref.origin)),
+        ("dead", Value::Bool(gref.state == RefState::Dead)),
+    ];
+    let target = match gref.target {
+        GlobalRefTarget::Board => "board",
+        GlobalRefTarget::Catalog => "catalog",
+        GlobalRefTarget::Thread(thread) => {
+            fields.push(("thread", Value::U64(thread)));
+            "thread"
+        },
+        GlobalRefTarget::Post(thread, post) => {
+            fields.push(("thread", Value::U64(thread)));
+            fields.push(("post", Value::U64(post)));
+            "post"
+        },
+        GlobalRefTarget::Search(ref query) => {
+            fields.push(("query", string(query.raw())));
+            fields.push(("terms", Value::Array(query.terms().iter().map(|t| string(t)).collect())));
+            "search"
+        },
+        GlobalRefTarget::Archive => "archive",
+        GlobalRefTarget::Page(page) => {
+            fields.push(("page", Value::U64(page as u64)));
+            "page"
+        },
+        GlobalRefTarget::Rule(rule) => {
+            fields.push(("rule", Value::U64(rule as u64)));
+            "rule"
+        },
+        GlobalRefTarget::Unresolved(post) => {
+            fields.push(("post", Value::U64(post)));
+            "unresolved"
+        },
+    };
+    fields.push(("target", string(target)));
+    object("globalref", fields)
+}
+
+fn node_value(node: &Node) -> Value {
+    match *node {
+        Node::List(ref children) => object("list", vec![("children", nodes(children))]),
+        Node::Encap(kind, ref child) => object(kind.as_str(), vec![("child", node_value(child))]),
+        Node::LocalRef(ref lref) => local_ref(lref),
+        Node::GlobalRef(ref gref) => global_ref(gref),
+        Node::Text(ref text) => object("text", vec![("text", string(text))]),
+        Node::Anchor(ref href, ref child) => object("anchor", vec![
+            ("href", string(href)),
+            ("child", node_value(child)),
+        ]),
+        Node::Unknown { ref tag, ref attrs, ref children } => object("unknown", vec![
+            ("tag", string(tag)),
+            ("attrs", pairs(attrs)),
+            ("child", node_value(children)),
+        ]),
+        Node::Math { display, ref tex } => object("math", vec![
+            ("display", Value::Bool(display)),
+            ("tex", string(tex)),
+        ]),
+        Node::Fortune { ref color, ref text } => object("fortune", vec![
+            ("color", opt_string(color)),
+            ("text", string(text)),
+        ]),
+        Node::DiceRoll { ref rolls, total, ref spec } => object("dice", vec![
+            ("rolls", Value::Array(rolls.iter().map(|&r| Value::U64(r as u64)).collect())),
+            ("total", Value::U64(total as u64)),
+            ("spec", opt_string(spec)),
+        ]),
+        Node::Truncated { thread, post } => object("truncated", vec![
+            ("thread", Value::U64(thread)),
+            ("post", Value::U64(post)),
+        ]),
+        Node::Exif(ref rows) => object("exif", vec![("rows", pairs(rows))]),
+        Node::Styled { ref color, ref weight, ref size, ref children } => object("styled", vec![
+            ("color", opt_string(color)),
+            ("weight", opt_string(weight)),
+            ("size", opt_string(size)),
+            ("child", node_value(children)),
+        ]),
+        Node::Paragraph(ref child) => object("paragraph", vec![("child", node_value(child))]),
+        Node::ListBlock { ordered, ref items } => object("listblock", vec![
+            ("ordered", Value::Bool(ordered)),
+            ("items", nodes(items)),
+        ]),
+        Node::Heading(level, ref child) => object("heading", vec![
+            ("level", Value::U64(level as u64)),
+            ("child", node_value(child)),
+        ]),
+        Node::Rule => object("hr", Vec::new()),
+        Node::LineBreak => object("br", Vec::new()),
+        Node::WordBreak => object("wbr", Vec::new()),
+    }
+}
+
+// {"version": N, "root": {"type": ..., ...}}
+pub fn to_value(node: &Node) -> Value {
+    let mut map = BTreeMap::new();
+    map.insert("version".to_string(), Value::U64(SCHEMA_VERSION));
+    map.insert("root".to_string(), node_value(node));
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::to_string;
+
+    use super::to_value;
+    use super::super::{Node, LocalRef, GlobalRef, GlobalRefTarget, RefState, PostContext};
+
+    #[test]
+    fn envelope() {
+        let node = Node::Text("hi".to_string());
+        assert_eq!(to_string(&to_value(&node)).unwrap(),
+            r#"{"root":{"text":"hi","type":"text"},"version":1}"#);
+    }
+
+    #[test]
+    fn refs() {
+        let gref = Node::GlobalRef(GlobalRef::new("a", GlobalRefTarget::post(123, 456)));
+        assert_eq!(to_string(&to_value(&gref)).unwrap(), concat!(
+            r#"{"root":{"board":"a","dead":false,"host":null,"origin":null,"post":456,"#,
+            r#""target":"post","thread":123,"type":"globalref"},"version":1}"#));
+
+        let cx = PostContext { board: "g".to_string(), thread: 400, post: 789 };
+        let lref = Node::LocalRef(LocalRef::new(99).with_context(cx).with_state(RefState::Dead));
+        assert_eq!(to_string(&to_value(&lref)).unwrap(), concat!(
+            r#"{"root":{"board":"g","dead":true,"in_post":789,"post":99,"thread":400,"#,
+            r#""type":"localref"},"version":1}"#));
+    }
+}
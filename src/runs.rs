@@ -0,0 +1,106 @@
+use super::{Node, EncapKind};
+
+fn is_inline_text(node: &Node) -> bool {
+    match *node {
+        Node::Text(_) | Node::LineBreak | Node::WordBreak => true,
+        _ => false,
+    }
+}
+
+pub fn run_text(run: &[Node]) -> String {
+    let mut out = String::new();
+    for node in run.iter() {
+        match *node {
+            Node::Text(ref text) => out.push_str(text),
+            Node::LineBreak => out.push('\n'),
+            _ => (),
+        }
+    }
+    out
+}
+
+// Copies the part of `run` lying between byte offsets `start` and `end` of
+// its run_text, keeping the original break nodes.
+pub fn copy_range(run: &[Node], start: usize, end: usize, out: &mut Vec<Node>) {
+    let mut pos = 0;
+    for node in run.iter() {
+        match *node {
+            Node::Text(ref text) => {
+                let lo = ::std::cmp::max(pos, start);
+                let hi = ::std::cmp::min(pos + text.len(), end);
+                if lo < hi {
+                    out.push(Node::Text(text[lo - pos..hi - pos].to_string()));
+                }
+                pos += text.len();
+            },
+            Node::LineBreak => {
+                if start <= pos && pos < end {
+                    out.push(Node::LineBreak);
+                }
+                pos += 1;
+            },
+            Node::WordBreak => {
+                if start <= pos && pos <= end {
+                    out.push(Node::WordBreak);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+// Rewrites every run of adjacent Text, LineBreak and WordBreak nodes with
+// `f`, leaving code blocks, shift-jis art and anchors untouched.
+pub fn map_runs<F>(node: &Node, f: &F) -> Node
+    where F: Fn(&[Node], &mut Vec<Node>)
+{
+    match *node {
+        Node::List(ref nodes) => {
+            let mut out = Vec::new();
+            let mut run = Vec::new();
+            for node in nodes.iter() {
+                if is_inline_text(node) {
+                    run.push(node.clone());
+                    continue;
+                }
+                f(&run, &mut out);
+                run.clear();
+                out.push(map_runs(node, f));
+            }
+            f(&run, &mut out);
+            Node::List(out)
+        },
+        Node::Text(_) => {
+            let mut out = Vec::new();
+            f(&[node.clone()], &mut out);
+            if out.len() == 1 {
+                out.pop().unwrap()
+            } else {
+                Node::List(out)
+            }
+        },
+        Node::Encap(EncapKind::Code, _) => node.clone(),
+        Node::Encap(EncapKind::Sjis, _) => node.clone(),
+        Node::Encap(kind, ref child) => {
+            Node::Encap(kind, Box::new(map_runs(child, f)))
+        },
+        Node::Unknown { ref tag, ref attrs, ref children } => Node::Unknown {
+            tag: tag.clone(),
+            attrs: attrs.clone(),
+            children: Box::new(map_runs(children, f)),
+        },
+        Node::Styled { ref color, ref weight, ref size, ref children } => Node::Styled {
+            color: color.clone(),
+            weight: weight.clone(),
+            size: size.clone(),
+            children: Box::new(map_runs(children, f)),
+        },
+        Node::Paragraph(ref child) => Node::Paragraph(Box::new(map_runs(child, f))),
+        Node::ListBlock { ordered, ref items } => Node::ListBlock {
+            ordered: ordered,
+            items: items.iter().map(|item| map_runs(item, f)).collect(),
+        },
+        Node::Heading(level, ref child) => Node::Heading(level, Box::new(map_runs(child, f))),
+        _ => node.clone(),
+    }
+}
@@ -0,0 +1,290 @@
+use serde::ser::{Serialize, Serializer};
+use serde::ser::impls::SeqIteratorVisitor;
+
+use super::{Node, EncapKind, LocalRef, GlobalRef, GlobalRefTarget, RefState};
+use super::tags;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(any(feature = "cbor", feature = "msgpack")), allow(dead_code))]
+pub enum TagStyle {
+    // "localref", as in the JSON tuple format
+    Name,
+    // the tag's index in the tags table, for the compact encodings
+    Interned,
+}
+
+struct Tag(&'static str, TagStyle);
+
+impl Serialize for Tag {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        let Tag(name, style) = *self;
+        match (style, tags::index(name)) {
+            (TagStyle::Interned, Some(index)) => index.serialize(serializer),
+            _ => name.serialize(serializer),
+        }
+    }
+}
+
+// A value paired with the tag style to write it (and everything under it)
+// with.
+pub struct Tagged<'a, T: 'a + ?Sized>(pub &'a T, pub TagStyle);
+
+impl Serialize for EncapKind {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        self.as_str().serialize(serializer)
+    }
+}
+
+impl<'a> Serialize for Tagged<'a, LocalRef> {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        let Tagged(lref, style) = *self;
+        let tag = Tag("localref", style);
+        match lref.context {
+            // ['localref', post_id]
+            None => (tag, lref.post).serialize(serializer),
+            // ['localref', post_id, board, thread, containing_post_id]
+            Some(ref cx) => {
+                (tag, lref.post, &cx.board, cx.thread, cx.post).serialize(serializer)
+            },
+        }
+    }
+}
+
+impl Serialize for LocalRef {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        Tagged(self, TagStyle::Name).serialize(serializer)
+    }
+}
+
+fn target_name(target: &GlobalRefTarget) -> &'static str {
+    match *target {
+        GlobalRefTarget::Board => "board",
+        GlobalRefTarget::Catalog => "catalog",
+        GlobalRefTarget::Thread(_) => "thread",
+        GlobalRefTarget::Post(_, _) => "post",
+        GlobalRefTarget::Search(_) => "search",
+        GlobalRefTarget::Archive => "archive",
+        GlobalRefTarget::Page(_) => "page",
+        GlobalRefTarget::Rule(_) => "rule",
+        GlobalRefTarget::Unresolved(_) => "unresolved",
+    }
+}
+
+impl<'a> Serialize for Tagged<'a, GlobalRefTarget> {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        // the globalref encoding without the leading 'globalref' and board:
+        // [target, ...]
+        let Tagged(target, style) = *self;
+        let tag = Tag(target_name(target), style);
+        match *target {
+            GlobalRefTarget::Thread(thread) => (tag, thread).serialize(serializer),
+            GlobalRefTarget::Post(thread, post) => (tag, thread, post).serialize(serializer),
+            GlobalRefTarget::Search(ref query) => (tag, &query.raw).serialize(serializer),
+            GlobalRefTarget::Page(page) => (tag, page).serialize(serializer),
+            GlobalRefTarget::Rule(rule) => (tag, rule).serialize(serializer),
+            GlobalRefTarget::Unresolved(post) => (tag, post).serialize(serializer),
+            GlobalRefTarget::Board |
+            GlobalRefTarget::Catalog |
+            GlobalRefTarget::Archive => (tag,).serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for GlobalRefTarget {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        Tagged(self, TagStyle::Name).serialize(serializer)
+    }
+}
+
+impl<'a> Serialize for Tagged<'a, GlobalRef> {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        // ['globalref', target, board, ...]
+        let Tagged(gref, style) = *self;
+        let tag = Tag("globalref", style);
+        let target = Tag(target_name(&gref.target), style);
+        let board = &gref.board;
+        match gref.target {
+            GlobalRefTarget::Thread(thread) => {
+                (tag, target, board, thread).serialize(serializer)
+            },
+            GlobalRefTarget::Post(thread, post) => {
+                (tag, target, board, thread, post).serialize(serializer)
+            },
+            GlobalRefTarget::Search(ref query) => {
+                (tag, target, board, &query.raw).serialize(serializer)
+            },
+            GlobalRefTarget::Page(page) => {
+                (tag, target, board, page).serialize(serializer)
+            },
+            GlobalRefTarget::Rule(rule) => {
+                (tag, target, board, rule).serialize(serializer)
+            },
+            GlobalRefTarget::Unresolved(post) => {
+                (tag, target, board, post).serialize(serializer)
+            },
+            GlobalRefTarget::Board |
+            GlobalRefTarget::Catalog |
+            GlobalRefTarget::Archive => {
+                (tag, target, board).serialize(serializer)
+            },
+        }
+    }
+}
+
+impl Serialize for GlobalRef {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        Tagged(self, TagStyle::Name).serialize(serializer)
+    }
+}
+
+impl<'a> Serialize for Tagged<'a, [Node]> {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        let Tagged(nodes, style) = *self;
+        let iter = nodes.iter().map(|node| Tagged(node, style));
+        serializer.serialize_seq(SeqIteratorVisitor::new(iter, Some(nodes.len())))
+    }
+}
+
+impl<'a> Serialize for Tagged<'a, Node> {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        use self::Node as N;
+        let Tagged(node, style) = *self;
+        let tag = |name: &'static str| Tag(name, style);
+        match *node {
+            N::List(ref nodes) => {
+                // ['list', [nodes]]
+                // Ideally this would be [list,  *nodes] tho ...
+                (tag("list"), Tagged(&nodes[..], style)).serialize(serializer)
+            },
+            N::Encap(kind, ref subnode) => {
+                // [kind, subnode]
+                (tag(kind.as_str()), Tagged(&**subnode, style)).serialize(serializer)
+            },
+            N::LocalRef(ref lref) => match lref.state {
+                RefState::Live => Tagged(lref, style).serialize(serializer),
+                // ['dead', ['localref', post_id]]
+                RefState::Dead => (tag("dead"), Tagged(lref, style)).serialize(serializer),
+            },
+            N::GlobalRef(ref gref) => {
+                let gref_tagged = Tagged(gref, style);
+                match (gref.state, gref.origin.as_ref()) {
+                    (RefState::Live, None) => gref_tagged.serialize(serializer),
+                    // ['archived', origin, ['globalref', ...]]
+                    (RefState::Live, Some(origin)) => {
+                        (tag("archived"), origin, gref_tagged).serialize(serializer)
+                    },
+                    // ['dead', ['globalref', ...]]
+                    (RefState::Dead, None) => (tag("dead"), gref_tagged).serialize(serializer),
+                    (RefState::Dead, Some(origin)) => {
+                        let archived = (tag("archived"), origin, gref_tagged);
+                        (tag("dead"), archived).serialize(serializer)
+                    },
+                }
+            },
+            N::Text(ref text) => {
+                // ['text', text]
+                (tag("text"), text).serialize(serializer)
+            },
+            N::Anchor(ref url, ref child) => {
+                (tag("anchor"), url, Tagged(&**child, style)).serialize(serializer)
+            },
+            N::Unknown { tag: ref name, ref attrs, ref children } => {
+                // ['unknown', tag, [[key, value], ...], children]
+                let children = Tagged(&**children, style);
+                (tag("unknown"), name, attrs, children).serialize(serializer)
+            },
+            N::Math { display: false, ref tex } => {
+                // ['math', tex]
+                (tag("math"), tex).serialize(serializer)
+            },
+            N::Math { display: true, ref tex } => {
+                // ['eqn', tex]
+                (tag("eqn"), tex).serialize(serializer)
+            },
+            N::Fortune { ref color, ref text } => {
+                // ['fortune', color, text]
+                (tag("fortune"), color, text).serialize(serializer)
+            },
+            N::DiceRoll { ref rolls, total, ref spec } => {
+                // ['dice', [rolls], total, spec]
+                (tag("dice"), rolls, total, spec).serialize(serializer)
+            },
+            N::Truncated { thread, post } => {
+                // ['truncated', thread, post]
+                (tag("truncated"), thread, post).serialize(serializer)
+            },
+            N::Exif(ref rows) => {
+                // ['exif', [[key, value], ...]]
+                (tag("exif"), rows).serialize(serializer)
+            },
+            N::Styled { ref color, ref weight, ref size, ref children } => {
+                // ['styled', color, weight, size, children]
+                let children = Tagged(&**children, style);
+                (tag("styled"), color, weight, size, children).serialize(serializer)
+            },
+            N::Paragraph(ref child) => {
+                // ['paragraph', child]
+                (tag("paragraph"), Tagged(&**child, style)).serialize(serializer)
+            },
+            N::ListBlock { ordered, ref items } => {
+                // ['listblock', ordered, [items]]
+                (tag("listblock"), ordered, Tagged(&items[..], style)).serialize(serializer)
+            },
+            N::Heading(level, ref child) => {
+                // ['heading', level, child]
+                (tag("heading"), level, Tagged(&**child, style)).serialize(serializer)
+            },
+            N::Rule => {
+                // ['hr']
+                (tag("hr"),).serialize(serializer)
+            },
+            N::LineBreak => {
+                // ['br']
+                (tag("br"),).serialize(serializer)
+            },
+            N::WordBreak => {
+                // ['wbr']
+                (tag("wbr"),).serialize(serializer)
+            }
+        }
+    }
+}
+
+impl Serialize for Node {
+    #[inline]
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer,
+    {
+        Tagged(self, TagStyle::Name).serialize(serializer)
+    }
+}
@@ -0,0 +1,50 @@
+// Tag strings of the tuple format, interned by position in the compact
+// encodings. Append only: an index, once written, must keep its meaning.
+const TAGS: [&'static str; 38] = [
+    "list",
+    "quote",
+    "spoiler",
+    "ban",
+    "code",
+    "sjis",
+    "bold",
+    "italic",
+    "underline",
+    "localref",
+    "globalref",
+    "dead",
+    "archived",
+    "text",
+    "anchor",
+    "unknown",
+    "math",
+    "eqn",
+    "fortune",
+    "dice",
+    "truncated",
+    "exif",
+    "styled",
+    "paragraph",
+    "listblock",
+    "heading",
+    "hr",
+    "br",
+    "wbr",
+    "board",
+    "catalog",
+    "thread",
+    "post",
+    "search",
+    "archive",
+    "page",
+    "rule",
+    "unresolved",
+];
+
+pub fn index(name: &str) -> Option<u64> {
+    TAGS.iter().position(|&tag| tag == name).map(|idx| idx as u64)
+}
+
+pub fn name(index: u64) -> Option<&'static str> {
+    TAGS.get(index as usize).map(|&tag| tag)
+}